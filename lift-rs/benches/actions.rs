@@ -0,0 +1,28 @@
+// Sending actions in a tight loop: a page of content links, each send looks its action up in
+// the story and saves a snapshot for back().
+mod common;
+
+use lift::{Interpreter, Story, Value};
+
+fn shop(links: usize) -> String {
+    let mut source = String::from("# Shop\n@set coins = 0\nYou have ${coins} coins.\n");
+    for i in 0..links {
+        source.push_str(&format!("@link Take coin {} {{ @set coins = coins + 1 }}\n", i));
+    }
+    source
+}
+
+fn send_all(interpreter: &mut Interpreter) {
+    interpreter.restart();
+    while let Some(&(index, _)) = interpreter.choices().first() {
+        interpreter.send(index, Value::Null).unwrap();
+    }
+}
+
+fn main() {
+    let source = || Story::new(&shop(100)).unwrap_or_else(|error| panic!("{}", error));
+    let mut interpreter = Interpreter::new(source());
+    common::bench("play, then send 100 content links", 200, || send_all(&mut interpreter));
+    let mut interpreter = Interpreter::new(source()).with_history_limit(0);
+    common::bench("the same without undo history", 200, || send_all(&mut interpreter));
+}
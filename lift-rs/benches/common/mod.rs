@@ -0,0 +1,18 @@
+// Timing without a benchmark framework. Each case runs in batches and the fastest batch is
+// reported per iteration, as it is the one least disturbed by other work on the machine.
+use std::time::{Duration, Instant};
+
+pub fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    for _ in 0..iterations / 10 + 1 {
+        f();
+    }
+    let mut best = Duration::MAX;
+    for _ in 0..10 {
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        best = best.min(start.elapsed() / iterations);
+    }
+    println!("{:<48} {:>12.2?}", name, best);
+}
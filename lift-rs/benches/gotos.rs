@@ -0,0 +1,21 @@
+// Chains of gotos, only the last page of the chain keeps its output.
+mod common;
+
+use lift::{Interpreter, Story};
+
+fn chain(length: usize) -> String {
+    let mut source = String::new();
+    for i in 0..length {
+        source.push_str(&format!("# Page {}\nText on page {} that is never shown.\n@goto Page {}\n\n", i, i, i + 1));
+    }
+    source.push_str(&format!("# Page {}\nThe end of the chain.\n", length));
+    source
+}
+
+fn main() {
+    for (length, iterations) in [(1, 20_000), (10, 5_000), (1000, 50)] {
+        let story = Story::new(&chain(length)).unwrap_or_else(|error| panic!("{}", error));
+        let mut interpreter = Interpreter::new(story).with_redirect_limit(length + 1).with_history_limit(0);
+        common::bench(&format!("chain of {} gotos", length), iterations, || interpreter.restart());
+    }
+}
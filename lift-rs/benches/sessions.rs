@@ -0,0 +1,67 @@
+// A typical play loop: a session plays the story and follows links until it ends. Interpreters
+// share the parsed story through an Arc, these cases show what that costs in the loop and that
+// sessions on several threads don't slow each other down.
+mod common;
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::thread;
+use lift::{Interpreter, Story, Value};
+
+const SOURCE: &str = "# Start
+@set gold = 10
+You stand at the gate with ${gold} gold.
+@link Enter -> Hall
+@link Leave -> End
+
+# Hall
+@set gold = gold + 5
+A hall full of ${gold} coins.
+@link Go on -> Tower
+
+# Tower
+@if gold > 12 {
+You climb with a heavy purse.
+}
+@link Finish -> End
+
+# End
+The end.
+";
+
+fn follow_links(interpreter: &mut Interpreter) {
+    while let Some(&(index, _)) = interpreter.choices().first() {
+        interpreter.send(index, Value::Null).unwrap();
+    }
+    black_box(interpreter.output().len());
+}
+
+fn sessions(story: &Arc<Story>, count: usize) {
+    for _ in 0..count {
+        let mut interpreter = Interpreter::from_shared(Arc::clone(story));
+        interpreter.play();
+        follow_links(&mut interpreter);
+    }
+}
+
+fn main() {
+    let source = || Story::new(SOURCE).unwrap_or_else(|error| panic!("{}", error));
+    let mut interpreter = Interpreter::new(source());
+    common::bench("session, restarting one interpreter", 2_000, || {
+        interpreter.restart();
+        follow_links(&mut interpreter);
+    });
+
+    let story = Arc::new(source());
+    let count = 1_000;
+    common::bench("1000 sessions on one thread", 5, || sessions(&story, count));
+    common::bench("1000 sessions on 4 threads, one shared story", 5, || {
+        let handles: Vec<_> = (0..4).map(|_| {
+            let story = Arc::clone(&story);
+            thread::spawn(move || sessions(&story, count / 4))
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+}
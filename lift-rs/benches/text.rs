@@ -0,0 +1,25 @@
+// A loop-heavy page: thousands of lines of static text, interpolated text and expressions,
+// all evaluated again each time the page is played.
+mod common;
+
+use lift::{Interpreter, Story};
+
+const SOURCE: &str = "# Loop
+@set total = 0
+@for i in range(1000) {
+A line of plain text that never changes.
+Line ${i} of the *loop*, running total ${total}.
+@set total = total + i * 2 - (i % 3 == 0 ? 1 : 0)
+}
+@set n = 0
+@while n < 1000 and total > 0 {
+@set n = n + 1
+}
+Done with ${n}.
+";
+
+fn main() {
+    let story = Story::new(SOURCE).unwrap_or_else(|error| panic!("{}", error));
+    let mut interpreter = Interpreter::new(story).with_history_limit(0);
+    common::bench("loop page, 1000 iterations", 50, || interpreter.restart());
+}
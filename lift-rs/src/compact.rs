@@ -0,0 +1,213 @@
+// Compact binary encoding of JSON values, used for saves where storage is limited.
+// Strings are stored once in a table and referenced by index, since keys such as "type"
+// and "value" repeat for every variable. Integers are varints and floats keep their exact bits.
+use std::collections::HashMap;
+use serde_json::{Map, Number, Value};
+
+const MAGIC: &[u8] = b"LFT";
+const FORMAT: u8 = 1;
+const MAX_DEPTH: usize = 512;
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const UNSIGNED: u8 = 3;
+const NEGATIVE: u8 = 4;
+const FLOAT: u8 = 5;
+const STRING: u8 = 6;
+const ARRAY: u8 = 7;
+const OBJECT: u8 = 8;
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut strings = HashMap::<&str, u64>::new();
+    let mut table = Vec::<&str>::new();
+    collect_strings(value, &mut strings, &mut table);
+
+    let mut data = MAGIC.to_vec();
+    data.push(FORMAT);
+    write_varint(&mut data, table.len() as u64);
+    for string in table {
+        write_varint(&mut data, string.len() as u64);
+        data.extend_from_slice(string.as_bytes());
+    }
+    write_value(&mut data, value, &strings);
+    data
+}
+
+pub fn decode(data: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { data, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not a lift save".to_string());
+    }
+    let format = reader.byte()?;
+    if format != FORMAT {
+        return Err(format!("unknown format {}", format));
+    }
+    let count = reader.varint()?;
+    let mut table = Vec::<String>::new();
+    for _ in 0..count {
+        let length = reader.length()?;
+        let bytes = reader.take(length)?;
+        table.push(String::from_utf8(bytes.to_vec()).map_err(|_| "invalid string".to_string())?);
+    }
+    let value = reader.value(&table, 0)?;
+    if reader.position != data.len() {
+        return Err("trailing data".to_string());
+    }
+    Ok(value)
+}
+
+fn add_string<'a>(string: &'a str, strings: &mut HashMap<&'a str, u64>, table: &mut Vec<&'a str>) {
+    if !strings.contains_key(string) {
+        strings.insert(string, table.len() as u64);
+        table.push(string);
+    }
+}
+
+fn collect_strings<'a>(value: &'a Value, strings: &mut HashMap<&'a str, u64>, table: &mut Vec<&'a str>) {
+    match value {
+        Value::String(string) => add_string(string, strings, table),
+        Value::Array(items) => {
+            for item in items {
+                collect_strings(item, strings, table);
+            }
+        }
+        Value::Object(entries) => {
+            for (key, item) in entries {
+                add_string(key, strings, table);
+                collect_strings(item, strings, table);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+fn write_value(data: &mut Vec<u8>, value: &Value, strings: &HashMap<&str, u64>) {
+    match value {
+        Value::Null => data.push(NULL),
+        Value::Bool(false) => data.push(FALSE),
+        Value::Bool(true) => data.push(TRUE),
+        Value::Number(number) => {
+            if let Some(unsigned) = number.as_u64() {
+                data.push(UNSIGNED);
+                write_varint(data, unsigned);
+            } else if let Some(signed) = number.as_i64() {
+                // Stored as -1 - n, so i64::MIN fits.
+                data.push(NEGATIVE);
+                write_varint(data, !(signed as u64));
+            } else {
+                data.push(FLOAT);
+                data.extend_from_slice(&number.as_f64().unwrap_or(0.0).to_bits().to_le_bytes());
+            }
+        }
+        Value::String(string) => {
+            data.push(STRING);
+            write_varint(data, strings[string.as_str()]);
+        }
+        Value::Array(items) => {
+            data.push(ARRAY);
+            write_varint(data, items.len() as u64);
+            for item in items {
+                write_value(data, item, strings);
+            }
+        }
+        Value::Object(entries) => {
+            data.push(OBJECT);
+            write_varint(data, entries.len() as u64);
+            for (key, item) in entries {
+                write_varint(data, strings[key.as_str()]);
+                write_value(data, item, strings);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(length).filter(|end| *end <= self.data.len()).ok_or("unexpected end of data")?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid number".to_string())
+    }
+
+    // A length can't be larger than the data left, which keeps corrupt saves from allocating memory.
+    fn length(&mut self) -> Result<usize, String> {
+        let length = self.varint()?;
+        match length <= (self.data.len() - self.position) as u64 {
+            true => Ok(length as usize),
+            false => Err("unexpected end of data".to_string())
+        }
+    }
+
+    fn string(&mut self, table: &[String]) -> Result<String, String> {
+        let index = self.varint()?;
+        table.get(index as usize).cloned().ok_or(format!("invalid string index {}", index))
+    }
+
+    fn value(&mut self, table: &[String], depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("data nested too deeply".to_string());
+        }
+        let value = match self.byte()? {
+            NULL => Value::Null,
+            FALSE => Value::Bool(false),
+            TRUE => Value::Bool(true),
+            UNSIGNED => Value::Number(self.varint()?.into()),
+            NEGATIVE => Value::Number((!self.varint()? as i64).into()),
+            FLOAT => {
+                let bytes: [u8; 8] = self.take(8)?.try_into().unwrap();
+                let float = f64::from_bits(u64::from_le_bytes(bytes));
+                Value::Number(Number::from_f64(float).ok_or("invalid float")?)
+            }
+            STRING => Value::String(self.string(table)?),
+            ARRAY => {
+                let length = self.length()?;
+                let mut items = vec![];
+                for _ in 0..length {
+                    items.push(self.value(table, depth + 1)?);
+                }
+                Value::Array(items)
+            }
+            OBJECT => {
+                let length = self.length()?;
+                let mut entries = Map::new();
+                for _ in 0..length {
+                    let key = self.string(table)?;
+                    entries.insert(key, self.value(table, depth + 1)?);
+                }
+                Value::Object(entries)
+            }
+            tag => return Err(format!("invalid tag {}", tag))
+        };
+        Ok(value)
+    }
+}
@@ -1,19 +1,61 @@
 // Content Parser
+use std::sync::{Arc, OnceLock};
 use serde::{Serialize, Deserialize};
+use regex::Regex;
 use crate::expression::*;
+use crate::value::{Value, comparison};
+use crate::markup::{self, Span};
 use crate::parser::{ContentParser, Parser, ContentToken, Params, ContentError};
 
+#[derive(Serialize, Deserialize)]
 pub enum Content {
     Text(TextContent),
-    Link(Action),
+    // A line written as ![alt](src)
+    Image { alt: TextContent, src: TextContent },
+    // @audio src (loop = expression, channel = expression)
+    Audio { src: TextContent, looping: Option<Expression>, channel: Option<Expression> },
+    Link { action: Action, guard: Option<Expression> },
+    // @wait ms, a pause the host makes before showing the elements after it.
+    Wait(Expression),
+    // @assert condition message "...", a self check of the story, only evaluated in strict mode.
+    Assert { condition: Expression, message: Option<Expression> },
+    // @setup { ... } also runs before the content of the pages nested in this one.
+    Setup { content: Vec<Content> },
+    // @field declares a field of the form submitted by the @submit after it, like an @input without a block.
+    Field { variable: String, kind: Option<InputType>, constraints: Vec<(String, Expression)> },
+    // @glossary term -> Page, shows the content of the page after the term instead of going to it.
+    Glossary { term: TextContent, page: TextContent },
     Set { local: bool, variable: String, indices: Vec<Expression>, expression: Expression },
-    If { expression: Expression, content: Vec<Content> },
+    // @let declares a variable that only exists until the end of the block it is in.
+    Let { variable: String, expression: Expression },
+    // @const declares a global that can't be assigned again.
+    Const { variable: String, expression: Expression },
+    // @default only sets the variable if it is unset or null, the expression isn't evaluated otherwise.
+    Default { variable: String, expression: Expression },
+    // An @if with the @elseif and @else blocks that follow it.
+    Conditional { branches: Vec<(Expression, Vec<Content>)>, else_branch: Option<Vec<Content>> },
+    // @elseif and @else are only kept outside of a conditional when no @if precedes them.
     ElseIf { expression: Expression, content: Vec<Content> },
     Else { content: Vec<Content> },
     For { index: Option<String>, variable: String, expression: Expression, content: Vec<Content> },
     While { expression: Expression, content: Vec<Content> },
+    Switch { expression: Expression, cases: Vec<(Expression, Vec<Content>)>, default: Option<Vec<Content>> },
+    Case { expression: Expression, content: Vec<Content> },
+    // Options without a weight have a weight of 1.
+    Random { options: Vec<(Option<Expression>, Vec<Content>)> },
+    RandomOption { weight: Option<Expression>, content: Vec<Content> },
+    // @clear removes the output shown before it.
+    Clear,
+    // @label name marks where a goto to "Page#name" starts the page.
+    Label(String),
+    Break,
+    Continue,
     Goto(TextContent),
-    Import(TextContent),
+    // Scoped imports run in a new local scope for the imported page instead of the caller's.
+    // Arguments are set as local variables of the scope the page runs in, the value given
+    // to @return by the imported page is stored in the caller's result variable.
+    Import { page: TextContent, scoped: bool, arguments: Vec<(String, Expression)>, result: Option<String> },
+    Return(Option<Expression>),
     Error(String)
 }
 
@@ -21,69 +63,271 @@ impl Content {
     fn make_error(message: &str) -> Content {
         Content::Error(message.to_string())
     }
+
+    fn is_blank(&self) -> bool {
+        match self {
+            Content::Text(text) => text.elements.iter().all(|element| matches!(element, TextElement::Text(s) if s.trim().is_empty())),
+            _ => false
+        }
+    }
+
+    // Adds an element to a block, attaching @elseif and @else to the conditional before them.
+    // Blank text between the blocks of a conditional is dropped, and consecutive blank lines are
+    // kept as one, since a blank line only separates paragraphs.
+    fn push(content: &mut Vec<Content>, mut element: Content) {
+        if element.is_blank() && content.last().is_some_and(Content::is_blank) {
+            return;
+        }
+        // A @submit takes the @field lines before it, along with the blank text between them.
+        if let Content::Link { action: Action::Form { fields, .. }, .. } = &mut element {
+            let start = content.iter()
+                .rposition(|element| !element.is_blank() && !matches!(element, Content::Field { .. }))
+                .map_or(0, |i| i + 1);
+            if content[start..].iter().any(|element| matches!(element, Content::Field { .. })) {
+                for field in content.drain(start..) {
+                    if let Content::Field { variable, kind, constraints } = field {
+                        fields.push((variable, kind, constraints));
+                    }
+                }
+            }
+        }
+        let start = content.iter().rposition(|element| !element.is_blank()).map_or(0, |i| i + 1);
+        let conditional = match (&element, start.checked_sub(1).map(|i| &mut content[i])) {
+            (Content::ElseIf { .. } | Content::Else { .. }, Some(Content::Conditional { branches, else_branch: else_branch @ None })) => {
+                Some((branches, else_branch))
+            }
+            _ => None
+        };
+        match (element, conditional) {
+            (Content::ElseIf { expression, content: block }, Some((branches, _))) => branches.push((expression, block)),
+            (Content::Else { content: block }, Some((_, else_branch))) => *else_branch = Some(block),
+            (Content::ElseIf { .. }, None) => {
+                content.push(Content::make_error("@elseif without a matching @if"));
+                return;
+            }
+            (element, None) => {
+                content.push(element);
+                return;
+            }
+            _ => {}
+        }
+        content.truncate(start);
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PageAction {
     pub page: String,
-    pub index: usize
+    pub index: usize,
+    // Once actions are hidden after being followed.
+    #[serde(default)]
+    pub once: bool,
+    // The action's content, so sending doesn't look the page up. Not saved, actions loaded from
+    // a compiled story or a saved state are resolved by the story instead.
+    #[serde(skip)]
+    pub content: Option<Arc<Vec<Content>>>
 }
 
 impl PageAction {
-    fn new(page: &str, index: usize) -> Self {
-        PageAction {page: page.to_string(), index}
+    // Adds the content to the page's actions.
+    fn new(page: &str, actions: &mut Vec<Arc<Vec<Content>>>, content: Vec<Content>, once: bool) -> Self {
+        let content = Arc::new(content);
+        actions.push(Arc::clone(&content));
+        PageAction {page: page.to_string(), index: actions.len() - 1, once, content: Some(content)}
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum InputType {
+    Number,
+    Text,
+    Bool
+}
+
+impl InputType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "number" => Some(InputType::Number),
+            "text" => Some(InputType::Text),
+            "bool" => Some(InputType::Bool),
+            _ => None
+        }
+    }
+}
+
+// Constraints of an input as shown to the player, error is set when the last value sent was rejected.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct InputSpec {
+    pub kind: Option<InputType>,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    pub pattern: Option<String>,
+    // Shown to label the field, and inside it while it's empty.
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub placeholder: Option<String>,
+    pub error: Option<String>
+}
+
+impl InputSpec {
+    // Converts the value to the input type and checks it against the constraints.
+    pub fn validate(&self, value: Value) -> Result<Value, String> {
+        let value = match (self.kind, value) {
+            (None, value) => return Ok(value),
+            (Some(InputType::Number), value @ (Value::Integer(_) | Value::Float(_))) => value,
+            (Some(InputType::Number), Value::Text(text)) => {
+                let text = text.trim();
+                if let Ok(integer) = text.parse::<i64>() {
+                    Value::Integer(integer)
+                } else {
+                    match text.parse::<f64>() {
+                        Ok(float) if float.is_finite() => Value::Float(float),
+                        _ => return Err("Expected a number".to_string())
+                    }
+                }
+            }
+            (Some(InputType::Number), _) => return Err("Expected a number".to_string()),
+            (Some(InputType::Bool), Value::Boolean(b)) => Value::Boolean(b),
+            (Some(InputType::Bool), Value::Text(text)) => match text.trim().to_lowercase().as_str() {
+                "true" | "yes" | "y" => Value::Boolean(true),
+                "false" | "no" | "n" => Value::Boolean(false),
+                _ => return Err("Expected yes or no".to_string())
+            },
+            (Some(InputType::Bool), _) => return Err("Expected yes or no".to_string()),
+            (Some(InputType::Text), Value::Text(text)) => Value::Text(text),
+            (Some(InputType::Text), value) => Value::Text(value.to_string())
+        };
+        // Text is limited by its length, numbers by their value.
+        let measure = match &value {
+            Value::Text(text) => Value::Integer(text.chars().count() as i64),
+            value => value.clone()
+        };
+        let unit = if let Value::Text(_) = value { " characters" } else { "" };
+        if let Some(min) = &self.min {
+            if comparison::lt(&measure, min).is_true() {
+                return Err(format!("Must be at least {}{}", min, unit));
+            }
+        }
+        if let Some(max) = &self.max {
+            if comparison::gt(&measure, max).is_true() {
+                return Err(format!("Must be at most {}{}", max, unit));
+            }
+        }
+        if let Some(pattern) = &self.pattern {
+            match Regex::new(pattern) {
+                Ok(regex) if regex.is_match(&value.to_string()) => {}
+                Ok(_) => return Err(format!("Must match {}", pattern)),
+                Err(_) => return Err(format!("Invalid pattern: {}", pattern))
+            }
+        }
+        Ok(value)
     }
 }
 
+// A form field's variable, input type and constraints.
+pub type FormField = (String, Option<InputType>, Vec<(String, Expression)>);
+
+#[derive(Serialize, Deserialize)]
 pub enum Action {
     Normal { title: TextContent, destination: TextContent },
     Content { title: TextContent, action: PageAction },
-    JumpLink { title: TextContent, destination: TextContent, action: PageAction},
-    Input { variable: String, action: PageAction }
+    // With a fallback, the destination is only taken if the action returns a true value.
+    JumpLink { title: TextContent, destination: TextContent, #[serde(default)] fallback: Option<TextContent>, action: PageAction},
+    Input { variable: String, action: PageAction, kind: Option<InputType>, constraints: Vec<(String, Expression)> },
+    // @submit title { ... } with the @field lines before it, the action runs once every field has a value.
+    Form { title: TextContent, action: PageAction, fields: Vec<FormField> }
     //Choice { title: String, variable: String, choices: String, content: Vec<Content> }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Page {
     pub content: Vec<Content>,
-    pub actions: Vec<Vec<Content>>
+    pub actions: Vec<Arc<Vec<Content>>>,
+    #[serde(default)]
+    pub tags: Vec<String>
 }
 
 impl Page {
     pub fn parse(title: &str, source: &str) -> Result<Page, (usize, ContentError)> {
         let mut content_stack: Vec<Vec<Content>> = vec![vec![]];
         let mut command_stack: Vec<(String, Vec<Params>)> = vec![];
-        let mut actions: Vec<Vec<Content>> = vec![];
+        let mut actions: Vec<Arc<Vec<Content>>> = vec![];
         let mut parser = ContentParser::new();
         let (tokens, size, error_option) = parser.parse(source);
         if let Some(error) = error_option {
-            return Err((size, error));
+            return Err((size + error.offset(), error));
         }
 
         for token in tokens {
             match token {
-                ContentToken::Text(text) => content_stack.last_mut().unwrap().push(Content::Text(text)),
+                ContentToken::Text(text) => {
+                    let content = match text.into_image() {
+                        Ok((alt, src)) => Content::Image { alt, src },
+                        Err(text) => Content::Text(text)
+                    };
+                    Content::push(content_stack.last_mut().unwrap(), content);
+                }
                 ContentToken::Command(name, mut params) => {
                     if let Some(Params::Block) = params.last() {
                         params.pop();
                         content_stack.push(vec![]);
                         command_stack.push((name, params));
+                    } else if (name == "break" || name == "continue") && !Self::in_loop(&command_stack) {
+                        Content::push(content_stack.last_mut().unwrap(), Content::make_error(&format!("@{} outside of a loop", name)));
                     } else {
-                        content_stack.last_mut().unwrap().push(Content::build_command(name, params, None, title, &mut actions));
+                        Content::push(content_stack.last_mut().unwrap(), Content::build_command(name, params, None, title, &mut actions));
                     }
                 }
                 ContentToken::BlockEnd => {
                     let block_contents = content_stack.pop();
                     let (name, params) = command_stack.pop().unwrap();
-                    content_stack.last_mut().unwrap().push(
+                    Content::push(
+                        content_stack.last_mut().unwrap(),
                         Content::build_command(name, params, block_contents, title, &mut actions)
-                    ); 
+                    );
                 }
             }
         }
         if command_stack.len() > 0 {
             return Err((size, ContentError::MissingClosingBrace));
         }
-        return Ok(Page { content: content_stack.pop().unwrap(), actions });
+        return Ok(Page { content: content_stack.pop().unwrap(), actions, tags: vec![] });
+    }
+
+    // The top level @setup blocks of this page.
+    pub fn setup(&self) -> Vec<&Vec<Content>> {
+        self.content.iter().filter_map(|content| match content {
+            Content::Setup { content } => Some(content),
+            _ => None
+        }).collect()
+    }
+
+    // Where the content after a top level @label starts, labels in blocks can't be jumped to.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.content.iter().position(|content| matches!(content, Content::Label(label) if label == name)).map(|index| index + 1)
+    }
+
+    // Every page reference made by this page, named by the command that makes it.
+    pub fn targets(&self) -> Vec<(&'static str, &TextContent)> {
+        let mut targets = vec![];
+        Content::collect_targets(&self.content, &mut targets);
+        for action in self.actions.iter() {
+            Content::collect_targets(action, &mut targets);
+        }
+        targets
+    }
+
+    // Actions run outside of the enclosing block, so loops don't extend past them.
+    fn in_loop(command_stack: &[(String, Vec<Params>)]) -> bool {
+        for (name, _) in command_stack.iter().rev() {
+            match name.as_str() {
+                "for" | "while" => return true,
+                "link" | "input" | "submit" => return false,
+                _ => {}
+            }
+        }
+        false
     }
 }
 
@@ -108,37 +352,115 @@ impl Args {
 }
 
 impl Content {
-    fn build_command(name: String, params: Vec<Params>, block: Option<Vec<Content>>, page: &str, actions: &mut Vec<Vec<Content>>) -> Content {
+    fn build_command(name: String, mut params: Vec<Params>, block: Option<Vec<Content>>, page: &str, actions: &mut Vec<Arc<Vec<Content>>>) -> Content {
+        // @once behaves like @link, but the link can only be followed a single time.
+        let once = name == "once";
+        let command = if once { "link" } else { name.as_str() };
+        // A trailing expression on a link is its guard: @link title -> destination @if expression
+        let guard = match (command, params.last()) {
+            ("link", Some(Params::Expression(_))) => match params.pop() {
+                Some(Params::Expression(expression)) => Some(expression),
+                _ => None
+            },
+            _ => None
+        };
+        let (named, params): (Vec<Params>, Vec<Params>) = params.into_iter().partition(|param| matches!(param, Params::Named(..)));
+        let named: Vec<(String, Expression)> = named.into_iter().filter_map(|param| match param {
+            Params::Named(name, expression) => Some((name, expression)),
+            _ => None
+        }).collect();
         let args = Args::from_params(params);
-        match (name.as_str(), args, block) {
+        match (command, args, block) {
+            // @link title -> success | failure { ... } picks a destination from what the content returns.
+            ("link", Args::Three(Params::Text(title), Params::Text(destination), Params::Text(fallback)), Some(content)) => {
+                Content::Link { action: Action::JumpLink{title, destination, fallback: Some(fallback), action: PageAction::new(page, actions, content, once)}, guard }
+            }
             ("link", Args::Two(Params::Text(title), Params::Text(destination)), Some(content)) => {
-                let action = actions.len();
-                actions.push(content);
-                Content::Link(Action::JumpLink{title, destination, action: PageAction::new(page, action)})
+                Content::Link { action: Action::JumpLink{title, destination, fallback: None, action: PageAction::new(page, actions, content, once)}, guard }
+            }
+            // Once links need an action to be tracked, so they become jump links with no content.
+            ("link", Args::Two(Params::Text(title), Params::Text(destination)), None) if once => {
+                Content::Link { action: Action::JumpLink{title, destination, fallback: None, action: PageAction::new(page, actions, vec![], once)}, guard }
             }
             ("link", Args::Two(Params::Text(title), Params::Text(destination)), None) => {
-                Content::Link(Action::Normal{title, destination})
+                Content::Link { action: Action::Normal{title, destination}, guard }
             }
             ("link", Args::One(Params::Text(title)), Some(content)) => {
-               let action = actions.len();
-               actions.push(content);
-               Content::Link(Action::Content{title, action: PageAction::new(page, action)})
+               Content::Link { action: Action::Content{title, action: PageAction::new(page, actions, content, once)}, guard }
+            }
+            ("setup", Args::Nothing, Some(content)) => Content::Setup { content },
+            ("glossary", Args::Two(Params::Text(term), Params::Text(page)), None) => {
+                Content::Glossary { term, page }
             }
             ("input", Args::One(Params::Variable(variable)), Some(content)) => {
-                let action = actions.len();
-                actions.push(content);
-                Content::Link(Action::Input{variable, action: PageAction::new(page, action)})
+                let action = PageAction::new(page, actions, content, false);
+                Content::Link { action: Action::Input{variable, action, kind: None, constraints: named}, guard: None }
+            }
+            ("input", Args::Two(Params::Variable(variable), Params::Variable(kind)), Some(content)) => {
+                let kind = match InputType::from_name(&kind) {
+                    Some(kind) => kind,
+                    None => return Content::make_error(&format!("Unknown input type: {}", kind))
+                };
+                let action = PageAction::new(page, actions, content, false);
+                Content::Link { action: Action::Input{variable, action, kind: Some(kind), constraints: named}, guard: None }
+            }
+            ("field", Args::One(Params::Variable(variable)), None) => Content::Field { variable, kind: None, constraints: named },
+            ("field", Args::Two(Params::Variable(variable), Params::Variable(kind)), None) => {
+                let kind = match InputType::from_name(&kind) {
+                    Some(kind) => kind,
+                    None => return Content::make_error(&format!("Unknown input type: {}", kind))
+                };
+                Content::Field { variable, kind: Some(kind), constraints: named }
+            }
+            ("submit", Args::One(Params::Text(title)), Some(content)) => {
+                let action = PageAction::new(page, actions, content, false);
+                Content::Link { action: Action::Form { title, action, fields: vec![] }, guard: None }
             }
             ("goto", Args::One(Params::Text(page)), None) => Content::Goto(page),
-            ("import", Args::One(Params::Text(page)), None) => Content::Import(page),
+            ("wait", Args::One(Params::Expression(expression)), None) => Content::Wait(expression),
+            ("assert", Args::One(Params::Expression(condition)), None) => {
+                let message = named.into_iter().find(|(name, _)| name == "message").map(|(_, expression)| expression);
+                Content::Assert { condition, message }
+            }
+            ("import", Args::One(Params::Text(page)), None) => Content::Import { page, scoped: false, arguments: named, result: None },
+            ("use", Args::One(Params::Text(page)), None) => Content::Import { page, scoped: true, arguments: named, result: None },
+            ("import", Args::Two(Params::Text(page), Params::Variable(result)), None) => {
+                Content::Import { page, scoped: false, arguments: named, result: Some(result) }
+            }
+            ("use", Args::Two(Params::Text(page), Params::Variable(result)), None) => {
+                Content::Import { page, scoped: true, arguments: named, result: Some(result) }
+            }
+            ("audio", Args::One(Params::Text(src)), None) => {
+                let mut looping = None;
+                let mut channel = None;
+                for (name, expression) in named {
+                    match name.as_str() {
+                        "loop" => looping = Some(expression),
+                        "channel" => channel = Some(expression),
+                        _ => return Content::make_error(&format!("Unknown @audio argument: {}", name))
+                    }
+                }
+                Content::Audio { src, looping, channel }
+            }
+            ("return", Args::One(Params::Expression(expression)), None) => Content::Return(Some(expression)),
+            ("return", Args::Nothing, None) => Content::Return(None),
             ("set", Args::Two(Params::Indices(variable, indices), Params::Expression(expression)), None) => {
                 Content::Set { local: false, variable, indices, expression }
             }
             ("setlocal", Args::Two(Params::Indices(variable, indices), Params::Expression(expression)), None) => {
                 Content::Set { local: true, variable, indices, expression }
             }
+            ("const", Args::Two(Params::Variable(variable), Params::Expression(expression)), None) => {
+                Content::Const { variable, expression }
+            }
+            ("let", Args::Two(Params::Variable(variable), Params::Expression(expression)), None) => {
+                Content::Let { variable, expression }
+            }
+            ("default", Args::Two(Params::Variable(variable), Params::Expression(expression)), None) => {
+                Content::Default { variable, expression }
+            }
             ("if", Args::One(Params::Expression(expression)), Some(content)) => {
-                Content::If { expression, content }
+                Content::Conditional { branches: vec![(expression, content)], else_branch: None }
             }
             ("elseif", Args::One(Params::Expression(expression)), Some(content)) => {
                 Content::ElseIf { expression, content }
@@ -155,30 +477,314 @@ impl Content {
             ("while", Args::One(Params::Expression(expression)), Some(content)) => {
                 Content::While { expression, content }
             }
+            ("switch", Args::One(Params::Expression(expression)), Some(content)) => {
+                Content::build_switch(expression, content)
+            }
+            ("case", Args::One(Params::Expression(expression)), Some(content)) => {
+                Content::Case { expression, content }
+            }
+            ("random", Args::Nothing, Some(content)) => {
+                Content::build_random(content)
+            }
+            ("option", Args::One(Params::Expression(weight)), Some(content)) => {
+                Content::RandomOption { weight: Some(weight), content }
+            }
+            ("option", Args::Nothing, Some(content)) => {
+                Content::RandomOption { weight: None, content }
+            }
+            ("clear", Args::Nothing, None) => Content::Clear,
+            ("label", Args::One(Params::Variable(name)), None) => Content::Label(name),
+            ("break", Args::Nothing, None) => Content::Break,
+            ("continue", Args::Nothing, None) => Content::Continue,
             _ => Content::make_error(&format!("Invalid command: {}", name))
         }
     }
+
+    fn collect_targets<'a>(content: &'a [Content], targets: &mut Vec<(&'static str, &'a TextContent)>) {
+        for element in content.iter() {
+            match element {
+                Content::Link { action: Action::Normal { destination, .. }, .. }
+                | Content::Link { action: Action::JumpLink { destination, fallback: None, .. }, .. } => targets.push(("link", destination)),
+                Content::Link { action: Action::JumpLink { destination, fallback: Some(fallback), .. }, .. } => {
+                    targets.push(("link", destination));
+                    targets.push(("link", fallback));
+                }
+                Content::Glossary { page, .. } => targets.push(("glossary", page)),
+                Content::Goto(page) => targets.push(("goto", page)),
+                Content::Import { page, scoped: false, .. } => targets.push(("import", page)),
+                Content::Import { page, scoped: true, .. } => targets.push(("use", page)),
+                | Content::ElseIf { content, .. }
+                | Content::Else { content }
+                | Content::For { content, .. }
+                | Content::While { content, .. }
+                | Content::Case { content, .. }
+                | Content::RandomOption { content, .. }
+                | Content::Setup { content } => Self::collect_targets(content, targets),
+                Content::Conditional { branches, else_branch } => {
+                    for (_, content) in branches.iter() {
+                        Self::collect_targets(content, targets);
+                    }
+                    if let Some(content) = else_branch {
+                        Self::collect_targets(content, targets);
+                    }
+                }
+                Content::Switch { cases, default, .. } => {
+                    for (_, content) in cases.iter() {
+                        Self::collect_targets(content, targets);
+                    }
+                    if let Some(content) = default {
+                        Self::collect_targets(content, targets);
+                    }
+                }
+                Content::Random { options } => {
+                    for (_, content) in options.iter() {
+                        Self::collect_targets(content, targets);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Texts, link titles and input variables in the content, taking every branch.
+    pub fn collect_outputs(content: &[Content], outputs: &mut (Vec<String>, Vec<String>, Vec<String>)) {
+        for element in content.iter() {
+            match element {
+                Content::Text(text) if !text.elements.is_empty() => outputs.0.push(text.template()),
+                Content::Link { action: Action::Normal { title, .. }, .. }
+                | Content::Link { action: Action::Content { title, .. }, .. }
+                | Content::Link { action: Action::JumpLink { title, .. }, .. }
+                | Content::Glossary { term: title, .. } => outputs.1.push(title.template()),
+                Content::Link { action: Action::Input { variable, .. }, .. } => outputs.2.push(variable.to_string()),
+                Content::Link { action: Action::Form { title, fields, .. }, .. } => {
+                    outputs.1.push(title.template());
+                    outputs.2.extend(fields.iter().map(|(variable, _, _)| variable.to_string()));
+                }
+                | Content::ElseIf { content, .. }
+                | Content::Else { content }
+                | Content::For { content, .. }
+                | Content::While { content, .. }
+                | Content::Case { content, .. }
+                | Content::RandomOption { content, .. }
+                | Content::Setup { content } => Self::collect_outputs(content, outputs),
+                Content::Conditional { branches, else_branch } => {
+                    for (_, content) in branches.iter() {
+                        Self::collect_outputs(content, outputs);
+                    }
+                    if let Some(content) = else_branch {
+                        Self::collect_outputs(content, outputs);
+                    }
+                }
+                Content::Switch { cases, default, .. } => {
+                    for (_, content) in cases.iter() {
+                        Self::collect_outputs(content, outputs);
+                    }
+                    if let Some(content) = default {
+                        Self::collect_outputs(content, outputs);
+                    }
+                }
+                Content::Random { options } => {
+                    for (_, content) in options.iter() {
+                        Self::collect_outputs(content, outputs);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn build_switch(expression: Expression, block: Vec<Content>) -> Content {
+        let mut cases = vec![];
+        let mut default = None;
+        for item in block {
+            match item {
+                Content::Case { expression, content } if default.is_none() => cases.push((expression, content)),
+                Content::Else { content } if default.is_none() => default = Some(content),
+                Content::Text(text) if text.elements.is_empty() => {}
+                _ => return Content::make_error("Invalid content in @switch, expected @case or @else")
+            }
+        }
+        Content::Switch { expression, cases, default }
+    }
+
+    fn build_random(block: Vec<Content>) -> Content {
+        let mut options = vec![];
+        for item in block {
+            match item {
+                Content::RandomOption { weight, content } => options.push((weight, content)),
+                Content::Text(text) if text.elements.is_empty() => {}
+                _ => return Content::make_error("Invalid content in @random, expected @option")
+            }
+        }
+        Content::Random { options }
+    }
 }
 
 
+#[derive(Serialize, Deserialize)]
 pub enum TextElement {
     Text(String),
+    // Characters written with a backslash, such as \* or \[, shown as they are.
+    Escaped(String),
     Variable(String),
-    Expression(Expression)
+    Expression(Expression),
+    Error(String)
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct TextContent {
-    pub elements: Vec<TextElement>
+    pub elements: Vec<TextElement>,
+    // Text without variables or expressions always has the same spans, so they are only found once.
+    #[serde(skip)]
+    spans: OnceLock<Vec<Span>>
 }
 
 impl TextContent {
+    pub fn new(elements: Vec<TextElement>) -> Self {
+        TextContent { elements, spans: OnceLock::new() }
+    }
+
     pub fn eval(&self, state: &impl StateManager) -> String {
-        return self.elements.iter().map(|element| {
+        let mut text = String::new();
+        for element in self.elements.iter() {
             match element {
-                TextElement::Text(s) => s.to_string(),
-                TextElement::Variable(var) => state.get(&var).map_or("null".to_string(), |x| x.to_string()),
-                TextElement::Expression(expr) => expr.eval(state).to_string()
+                TextElement::Text(s) | TextElement::Escaped(s) => text.push_str(s),
+                element => text.push_str(&Self::eval_element(element, state))
             }
-        }).collect::<Vec<_>>().join("");
+        }
+        return text;
+    }
+
+    // Splits text written as ![alt](src) into its alt text and source, other text is returned unchanged.
+    fn into_image(self) -> Result<(TextContent, TextContent), TextContent> {
+        let last = self.elements.len().saturating_sub(1);
+        let is_image = match (self.elements.first(), self.elements.last()) {
+            (Some(TextElement::Text(first)), Some(TextElement::Text(end))) => first.starts_with("![") && end.ends_with(')'),
+            _ => false
+        };
+        // The separator is the first ]( after the opening ![
+        let separator = self.elements.iter().enumerate().find_map(|(i, element)| match element {
+            TextElement::Text(s) if i == 0 => s[2.min(s.len())..].find("](").map(|index| (i, index + 2)),
+            TextElement::Text(s) => s.find("](").map(|index| (i, index)),
+            _ => None
+        });
+        let (separator, split) = match (is_image, separator) {
+            (true, Some(separator)) => separator,
+            _ => return Err(self)
+        };
+        // Only the closing parenthesis may follow the source.
+        let closings: usize = self.elements.iter().enumerate().skip(separator).map(|(i, element)| match element {
+            TextElement::Text(s) if i == separator => s[split..].matches(')').count(),
+            TextElement::Text(s) => s.matches(')').count(),
+            _ => 0
+        }).sum();
+        if closings != 1 {
+            return Err(self);
+        }
+
+        let mut alt = vec![];
+        let mut src = vec![];
+        for (i, element) in self.elements.into_iter().enumerate() {
+            let element = match element {
+                TextElement::Text(mut s) => {
+                    if i == last {
+                        s.pop();
+                    }
+                    if i == separator {
+                        let start = if i == 0 { 2 } else { 0 };
+                        let (before, after) = (s[start..split].to_string(), s[split + "](".len()..].to_string());
+                        if !before.is_empty() {
+                            alt.push(TextElement::Text(before));
+                        }
+                        if !after.is_empty() {
+                            src.push(TextElement::Text(after));
+                        }
+                        continue;
+                    }
+                    if i == 0 {
+                        s.drain(..2);
+                    }
+                    TextElement::Text(s)
+                }
+                element => element
+            };
+            match i < separator {
+                true => alt.push(element),
+                false => src.push(element)
+            }
+        }
+        Ok((TextContent::new(alt), TextContent::new(src)))
+    }
+
+    // Markup is only read from the text itself, not from values written into it.
+    pub fn eval_spans(&self, state: &impl StateManager) -> Vec<Span> {
+        let is_static = self.elements.iter().all(|element| matches!(element, TextElement::Text(_) | TextElement::Escaped(_)));
+        if is_static {
+            return self.spans.get_or_init(|| self.find_spans(state)).clone();
+        }
+        self.find_spans(state)
+    }
+
+    fn find_spans(&self, state: &impl StateManager) -> Vec<Span> {
+        let mut text = String::new();
+        let mut markup = vec![];
+        for element in self.elements.iter() {
+            let s = match element {
+                TextElement::Text(s) | TextElement::Escaped(s) => std::borrow::Cow::Borrowed(s.as_str()),
+                element => std::borrow::Cow::Owned(Self::eval_element(element, state))
+            };
+            markup.extend(s.chars().map(|_| matches!(element, TextElement::Text(_))));
+            text.push_str(&s);
+        }
+        markup::spans(&text, &markup)
+    }
+
+    fn eval_element(element: &TextElement, state: &impl StateManager) -> String {
+        match element {
+            TextElement::Text(s) | TextElement::Escaped(s) => s.to_string(),
+            TextElement::Variable(var) => state.get(var).map_or("null".to_string(), |x| state.format(x)),
+            TextElement::Expression(expr) => state.format(&expr.eval(state)),
+            TextElement::Error(source) => format!("[invalid expression: {}]", source)
+        }
+    }
+
+    // Whether the text could evaluate to the given string, judging from its static prefix and suffix.
+    pub fn could_match(&self, text: &str) -> bool {
+        let mut prefix = String::new();
+        for element in self.elements.iter() {
+            match element {
+                TextElement::Text(s) | TextElement::Escaped(s) => prefix.push_str(s),
+                _ => break
+            }
+        }
+        let mut suffix = String::new();
+        for element in self.elements.iter().rev() {
+            match element {
+                TextElement::Text(s) | TextElement::Escaped(s) => suffix.insert_str(0, s),
+                _ => break
+            }
+        }
+        text.len() >= prefix.len() + suffix.len() && text.starts_with(&prefix) && text.ends_with(&suffix)
+    }
+
+    // The text with variables shown as $name and expressions as ${...}.
+    pub fn template(&self) -> String {
+        self.elements.iter().map(|element| match element {
+            TextElement::Text(s) | TextElement::Escaped(s) => s.to_string(),
+            TextElement::Variable(var) => format!("${}", var),
+            TextElement::Expression(_) | TextElement::Error(_) => "${...}".to_string()
+        }).collect()
+    }
+
+    // The text, if it doesn't depend on any variable or expression.
+    pub fn as_static(&self) -> Option<String> {
+        let mut text = String::new();
+        for element in self.elements.iter() {
+            match element {
+                TextElement::Text(s) | TextElement::Escaped(s) => text.push_str(s),
+                _ => return None
+            }
+        }
+        Some(text)
     }
 }
@@ -1,11 +1,59 @@
 use std::collections::HashMap;
+use std::sync::OnceLock;
+use serde::{Serialize, Deserialize};
 use crate::value::{Value, Value::*, comparison, operator};
 
 pub trait StateManager {
     fn get(&self, variable: &str) -> Option<&Value>;
+
+    fn call(&self, function: &str, arguments: Vec<Value>) -> Value {
+        Value::eval_function(function, arguments).unwrap_or(Null)
+    }
+
+    // How values are written into text.
+    fn format(&self, value: &Value) -> String {
+        value.to_string()
+    }
+
+    // Called when an operation fails, the failed value is always null.
+    fn error(&self, _message: String) {}
+}
+
+// Send, so interpreters can move between threads.
+pub type HostFunction = Box<dyn Fn(&[Value]) -> Value + Send>;
+
+// Functions provided by the host application, callable from expressions.
+#[derive(Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, (Option<usize>, HostFunction)>
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        FunctionRegistry { functions: HashMap::new() }
+    }
+
+    pub fn register(&mut self, name: &str, function: HostFunction) {
+        self.functions.insert(name.to_string(), (None, function));
+    }
+
+    // Calls with a different number of arguments are reported as errors.
+    pub fn register_with_arity(&mut self, name: &str, arity: usize, function: HostFunction) {
+        self.functions.insert(name.to_string(), (Some(arity), function));
+    }
+
+    pub fn call(&self, name: &str, arguments: &[Value]) -> Option<Result<Value, String>> {
+        let (arity, function) = self.functions.get(name)?;
+        match arity {
+            Some(n) if *n != arguments.len() => Some(Err(format!(
+                "Function '{}' expects {} argument(s), got {}", name, n, arguments.len()
+            ))),
+            _ => Some(Ok(function(arguments)))
+        }
+    }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum Operator {
     Add, Sub, Mul, Div, Rem, Exp,
     And, Or,
@@ -40,6 +88,35 @@ impl Operator {
         }
     }
 
+    fn symbol(&self) -> Option<&'static str> {
+        match self {
+            Exp => Some("^"),
+            Add => Some("+"),
+            Mul => Some("*"),
+            Sub => Some("-"),
+            Div => Some("/"),
+            Rem => Some("%"),
+            _ => None
+        }
+    }
+
+    // Explains why an arithmetic operation on non-null operands resulted in null.
+    fn error(&self, a: &str, b: &str, divisor_is_zero: bool) -> Option<String> {
+        let symbol = self.symbol()?;
+        match (a, b) {
+            ("null", _) | (_, "null") => None,
+            ("integer" | "float", _) if matches!(self, Div | Rem) && divisor_is_zero => {
+                Some("Division by zero".to_string())
+            }
+            ("integer", "integer") => Some(format!("Integer overflow in '{}'", symbol)),
+            // Text is never converted implicitly, inputs arrive as text and need number().
+            ("text", "integer" | "float") | ("integer" | "float", "text") if !matches!(self, Mul) => Some(format!(
+                "Invalid operands for '{}': {} and {}, use number() to convert text", symbol, a, b
+            )),
+            _ => Some(format!("Invalid operands for '{}': {} and {}", symbol, a, b))
+        }
+    }
+
     fn apply(&self, a: Value, b: Value) -> Value {
         match self {
             Exp => a.pow(&b),
@@ -64,7 +141,7 @@ impl Operator {
     }
 }
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum UnaryOperator {
     Minus, Plus, Not
 }
@@ -86,6 +163,7 @@ impl UnaryOperator {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum ExpressionToken {
     Constant(Value),
     Operator(Operator),
@@ -93,18 +171,74 @@ pub enum ExpressionToken {
     Function(String, usize),
     Array(usize),
     Object(usize),
-    Variable(String)
+    Variable(String),
+    // condition ? then : else, takes the three values in that order.
+    Conditional
+}
+
+// Positions are indices into the tokens of an expression.
+struct Jumps {
+    // Start of the right operand of an and, or or ?? to the operator.
+    operands: HashMap<usize, usize>,
+    // Start of the then branch of a conditional to the start of the else branch and the conditional.
+    branches: HashMap<usize, (usize, usize)>,
+    // Start of the else branch to the conditional, skipped when the then branch ran.
+    else_branches: HashMap<usize, usize>
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Expression {
-    pub tokens: Vec<ExpressionToken>
+    pub tokens: Vec<ExpressionToken>,
+    // Found on the first eval and kept, since the tokens of an expression never change.
+    #[serde(skip)]
+    jumps: OnceLock<Option<Jumps>>
 }
 
 impl Expression {
+    pub fn new(tokens: Vec<ExpressionToken>) -> Self {
+        Expression { tokens, jumps: OnceLock::new() }
+    }
+
     pub fn eval(&self, state: &impl StateManager) -> Value {
         let mut value_stack = Vec::<Value>::new();
+        let jumps = self.jumps.get_or_init(|| self.find_jumps());
 
-        for token in self.tokens.iter() {
+        let mut position = 0;
+        // Set when jumping to an else branch, so reaching it doesn't skip it as if the then branch had run.
+        let mut in_else = false;
+        while position < self.tokens.len() {
+            let current = position;
+            let token = &self.tokens[current];
+            position += 1;
+            if let (Some(jumps), false) = (jumps, std::mem::take(&mut in_else)) {
+                // The left operand of an and, or or ?? is on top of the stack, skip the right one if it decides the result.
+                if let (Some(operator), Some(left)) = (jumps.operands.get(&current), value_stack.last_mut()) {
+                    let decided = match &self.tokens[*operator] {
+                        ExpressionToken::Operator(Operator::And) => !left.is_true(),
+                        ExpressionToken::Operator(Operator::Or) => left.is_true(),
+                        _ => !matches!(left, Null)
+                    };
+                    if decided {
+                        if !matches!(&self.tokens[*operator], ExpressionToken::Operator(Operator::Coalesce)) {
+                            *left = Boolean(left.is_true());
+                        }
+                        position = operator + 1;
+                        continue;
+                    }
+                }
+                // The condition is on top of the stack, only the branch it selects is evaluated.
+                if let Some((else_start, _)) = jumps.branches.get(&current) {
+                    if !value_stack.pop().unwrap_or(Null).is_true() {
+                        position = *else_start;
+                        in_else = true;
+                        continue;
+                    }
+                }
+                else if let Some(operator) = jumps.else_branches.get(&current) {
+                    position = operator + 1;
+                    continue;
+                }
+            }
             match token {
                 ExpressionToken::Constant(value) => value_stack.push(value.clone()),
                 ExpressionToken::Variable(var) => {
@@ -116,7 +250,19 @@ impl Expression {
                     }
                     let b = value_stack.pop().unwrap();
                     let a = value_stack.pop().unwrap();
-                    value_stack.push(op.apply(a, b));
+                    if let (Index, Array(items), Integer(i)) = (op, &a, &b) {
+                        if *i < 0 || *i as usize >= items.len() {
+                            state.error(format!("Index {} out of bounds for array of length {}", i, items.len()));
+                        }
+                    }
+                    let operands = (a.type_name(), b.type_name(), b.is_zero());
+                    let value = op.apply(a, b);
+                    if let Null = value {
+                        if let Some(error) = op.error(operands.0, operands.1, operands.2) {
+                            state.error(error);
+                        }
+                    }
+                    value_stack.push(value);
                 },
                 ExpressionToken::UnaryOperator(op) => {
                     match value_stack.pop() {
@@ -145,19 +291,179 @@ impl Expression {
                     }
                     value_stack.push(Object(obj));
                 }
+                // Only the selected branch is on the stack, unless the expression couldn't be checked for jumps.
+                ExpressionToken::Conditional if jumps.is_none() => {
+                    if value_stack.len() < 3 {
+                        return Null;
+                    }
+                    let otherwise = value_stack.pop().unwrap();
+                    let then = value_stack.pop().unwrap();
+                    let condition = value_stack.pop().unwrap();
+                    value_stack.push(if condition.is_true() { then } else { otherwise });
+                }
+                ExpressionToken::Conditional => {}
                 ExpressionToken::Function(function, arguments) => {
                     if value_stack.len() < *arguments {
                         return Null;
                     }
                     let argument_stack = value_stack.split_off(value_stack.len() - arguments);
-                    value_stack.push(Value::eval_function(function, argument_stack));
+                    value_stack.push(state.call(function, argument_stack));
                 }
             }
         }
         return value_stack.pop().unwrap_or(Null);
     }
 
+    // Whether the tokens leave exactly one value, so 1 + isn't mistaken for a complete expression.
+    pub fn is_complete(&self) -> bool {
+        let mut depth = 0;
+        for token in &self.tokens {
+            let operands = match token {
+                ExpressionToken::Constant(_) | ExpressionToken::Variable(_) => 0,
+                ExpressionToken::Operator(_) => 2,
+                ExpressionToken::UnaryOperator(_) => 1,
+                ExpressionToken::Conditional => 3,
+                ExpressionToken::Function(_, arguments) | ExpressionToken::Array(arguments) => *arguments,
+                ExpressionToken::Object(elements) => elements * 2
+            };
+            if operands > depth {
+                return false;
+            }
+            depth = depth - operands + 1;
+        }
+        depth == 1
+    }
+
+    // Where eval can skip operands that don't change the result. None if the tokens don't form a valid expression.
+    fn find_jumps(&self) -> Option<Jumps> {
+        let mut jumps = Jumps { operands: HashMap::new(), branches: HashMap::new(), else_branches: HashMap::new() };
+        let jumps_from = |token: &ExpressionToken| matches!(token,
+            ExpressionToken::Operator(Operator::And | Operator::Or | Operator::Coalesce) | ExpressionToken::Conditional);
+        if !self.tokens.iter().any(jumps_from) {
+            return Some(jumps);
+        }
+        // Where the tokens that computed each value on the stack start.
+        let mut starts = Vec::<usize>::new();
+        for (position, token) in self.tokens.iter().enumerate() {
+            let operands = match token {
+                ExpressionToken::Constant(_) | ExpressionToken::Variable(_) => 0,
+                ExpressionToken::Operator(_) => 2,
+                ExpressionToken::UnaryOperator(_) => 1,
+                ExpressionToken::Conditional => 3,
+                ExpressionToken::Function(_, arguments) | ExpressionToken::Array(arguments) => *arguments,
+                ExpressionToken::Object(elements) => elements * 2
+            };
+            if operands > starts.len() {
+                return None;
+            }
+            match (token, starts.as_slice()) {
+                (ExpressionToken::Conditional, [.., _, then, otherwise]) => {
+                    jumps.branches.insert(*then, (*otherwise, position));
+                    jumps.else_branches.insert(*otherwise, position);
+                }
+                (token, [.., right]) if jumps_from(token) => {
+                    jumps.operands.insert(*right, position);
+                }
+                _ => {}
+            }
+            let start = match operands {
+                0 => position,
+                _ => starts[starts.len() - operands]
+            };
+            starts.truncate(starts.len() - operands);
+            starts.push(start);
+        }
+        Some(jumps)
+    }
+
     pub fn constant(value: Value) -> Self {
-        Expression { tokens: vec![ExpressionToken::Constant(value)] }
+        Expression::new(vec![ExpressionToken::Constant(value)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::expression_parser::ExpressionParser;
+
+    // Records every function called, so tests can tell which operands were evaluated.
+    #[derive(Default)]
+    struct Recorder {
+        variables: HashMap<String, Value>,
+        calls: RefCell<Vec<String>>
+    }
+
+    impl StateManager for Recorder {
+        fn get(&self, variable: &str) -> Option<&Value> {
+            self.variables.get(variable)
+        }
+
+        fn call(&self, function: &str, arguments: Vec<Value>) -> Value {
+            self.calls.borrow_mut().push(function.to_string());
+            arguments.into_iter().next().unwrap_or(Null)
+        }
+    }
+
+    fn eval(source: &str, state: &Recorder) -> Value {
+        match ExpressionParser::parse(source) {
+            (Ok(expression), size) if size == source.len() => expression.eval(state),
+            _ => panic!("'{}' doesn't parse", source)
+        }
+    }
+
+    #[test]
+    fn and_or_skip_the_right_operand() {
+        let state = Recorder::default();
+        assert_eq!(eval("false && touch(true)", &state), Boolean(false));
+        assert_eq!(eval("true || touch(false)", &state), Boolean(true));
+        assert_eq!(eval("0 and touch(1)", &state), Boolean(false));
+        assert_eq!(eval("\"yes\" or touch(1)", &state), Boolean(true));
+        assert!(state.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn and_or_evaluate_the_right_operand_when_needed() {
+        let state = Recorder::default();
+        assert_eq!(eval("true && touch(0)", &state), Boolean(false));
+        assert_eq!(eval("false || touch([1])", &state), Boolean(true));
+        assert_eq!(state.calls.borrow().len(), 2);
+    }
+
+    #[test]
+    fn coalesce_skips_the_right_operand_when_the_left_is_set() {
+        let mut state = Recorder::default();
+        state.variables.insert("name".to_string(), Text("Ann".to_string()));
+        state.variables.insert("zero".to_string(), Integer(0));
+        assert_eq!(eval("name ?? touch(\"Anonymous\")", &state), Text("Ann".to_string()));
+        // Falsy values that aren't null are kept too.
+        assert_eq!(eval("zero ?? touch(5)", &state), Integer(0));
+        assert!(state.calls.borrow().is_empty());
+        assert_eq!(eval("missing ?? touch(5)", &state), Integer(5));
+        assert_eq!(*state.calls.borrow(), vec!["touch"]);
+    }
+
+    #[test]
+    fn truthiness() {
+        let state = Recorder::default();
+        let cases = [
+            ("null", false), ("0", false), ("0.0", false), ("\"\"", false), ("[]", false), ("{}", false),
+            ("false", false), ("1", true), ("-1", true), ("0.5", true), ("\"0\"", true), ("\" \"", true),
+            ("[0]", true), ("{\"a\": 0}", true), ("true", true)
+        ];
+        for (source, truthy) in cases {
+            assert_eq!(eval(source, &state).is_true(), truthy, "{}", source);
+            assert_eq!(eval(&format!("not {}", source), &state), Boolean(!truthy), "not {}", source);
+            assert_eq!(eval(&format!("!{}", source), &state), Boolean(!truthy), "!{}", source);
+        }
+    }
+
+    #[test]
+    fn bool_literals() {
+        let state = Recorder::default();
+        assert_eq!(eval("true", &state), Boolean(true));
+        assert_eq!(eval("false", &state), Boolean(false));
+        assert_eq!(eval("true == !false", &state), Boolean(true));
+        assert_eq!(eval("true && false", &state), Boolean(false));
     }
 }
@@ -13,6 +13,9 @@ pub enum ParserToken {
     IndexStart,  IndexEnd,
     Separator,
     ObjectSeparator,
+    // The ? and : of a conditional.
+    Question,
+    TernaryElse,
     Function(String),
     Constant(Value),
     Operator(Operator),
@@ -47,7 +50,7 @@ impl ParserToken {
 
     fn is_operator(&self) -> bool {
         match self {
-            Operator(_) | UnaryOperator(_) => true,
+            Operator(_) | UnaryOperator(_) | TernaryElse => true,
             _ => false
         }
     }
@@ -56,15 +59,19 @@ impl ParserToken {
         match self {
             Operator(op) => Some(ExpressionToken::Operator(op)),
             UnaryOperator(op) => Some(ExpressionToken::UnaryOperator(op)),
+            TernaryElse => Some(ExpressionToken::Conditional),
             _ => None
         }
     }
 
     fn invalid_pair(previous: Option<&Self>, token: Option<&Self>) -> bool {
         match (previous, token) {
-            (None, Some(Operator(_))) => return true,
+            (None, Some(Operator(_) | Question | TernaryElse)) => return true,
+            (Some(a), Some(Question | TernaryElse)) => {
+                return !(a.is_value() || a.is_end_token() || matches!(a, IndexEnd | ObjectIndex(_)));
+            }
             (Some(a), Some(Operator(_))) => {
-                return a.is_operator();
+                return a.is_operator() || a == &Question;
             },
             (Some(ObjectIndex(_)), Some(a)) => {
                 return a.is_start_token() || a.is_value();
@@ -79,7 +86,9 @@ impl ParserToken {
                 return (a.is_end_token() && b.is_start_token())
                 || (a.is_value() && b.is_value())
                 || (a.is_value() && b.is_start_token())
-                || (a.is_end_token() && b.is_value());
+                || (a.is_end_token() && b.is_value())
+                // A unary operator can't follow an operand, "x !y" is text after the expression.
+                || ((a.is_value() || a.is_end_token()) && matches!(b, UnaryOperator(_)));
             }
             _ => return false
         }
@@ -95,10 +104,20 @@ enum ListType {
 pub struct ExpressionParser {
     last: Option<ParserToken>,
     token_stack: Vec<ParserToken>,
-    size: usize
+    size: usize,
+    // Set by parse_line, for commands whose expression is optional and may be followed by text.
+    ends_at_line: bool
 }
 
 impl ExpressionParser {
+    // Whether the last token ends an operand, so what follows is a binary operator.
+    fn follows_value(&self) -> bool {
+        match &self.last {
+            Some(token) => token.is_value() || token.is_end_token() || matches!(token, IndexEnd | ObjectIndex(_)),
+            None => false
+        }
+    }
+
     fn get_token(&mut self, regex: &Regex, search: &str) -> Option<String> {
         let capture = regex.captures(search)?;
         // Consume the entire regex match or only the first capture group.
@@ -108,7 +127,7 @@ impl ExpressionParser {
     }
 
     pub fn new() -> Self {
-        return Self { last: None, token_stack: Vec::new(), size: 0};
+        return Self { last: None, token_stack: Vec::new(), size: 0, ends_at_line: false };
     }
 
     pub fn parse_indices(tokens: Vec<ParserToken>) -> Option<(String, Vec<Expression>)> {
@@ -131,6 +150,7 @@ impl ExpressionParser {
                     token_stack.push(token);
                 }
                 (IndexEnd, 1) => {
+                    bracket_counter -= 1;
                     if let Ok(expression) = Self::convert_to_postfix(token_stack) {
                         expression_stack.push(expression);
                     }
@@ -193,6 +213,19 @@ impl ExpressionParser {
                 }
                 ObjectSeparator => {
                 }
+                // The conditional has the lowest precedence and groups to the right, so a ? b : c ? d : e
+                // is a ? b : (c ? d : e).
+                Question => {
+                    Self::pop_while(&mut operator_stack, &mut return_expression, |x| x != &TernaryElse);
+                    operator_stack.push(token);
+                }
+                TernaryElse => {
+                    Self::pop_while(&mut operator_stack, &mut return_expression, |x| x != &Question);
+                    if operator_stack.pop() != Some(Question) {
+                        return Err(ParsingError::InvalidConditional);
+                    }
+                    operator_stack.push(token);
+                }
                 Separator => {
                     Self::pop_while(&mut operator_stack, &mut return_expression, |_| true);
                     if let Some((arg_count, list_type)) = function_stack.pop() {
@@ -274,11 +307,22 @@ impl ExpressionParser {
             previous_token = Some(token_reference.clone());
         }
         Self::pop_while(&mut operator_stack, &mut return_expression, |_| true);
-        return Ok(Expression { tokens: return_expression });
+        if operator_stack.contains(&Question) {
+            return Err(ParsingError::InvalidConditional);
+        }
+        return Ok(Expression::new(return_expression));
     }
 
     pub fn parse(string: &str) -> (Result<Expression, ParsingError>, usize) {
-        let mut parser = Self::new();
+        Self::parse_with(Self::new(), string)
+    }
+
+    // Parses an expression that ends at the end of its line.
+    pub fn parse_line(string: &str) -> (Result<Expression, ParsingError>, usize) {
+        Self::parse_with(Self { ends_at_line: true, ..Self::new() }, string)
+    }
+
+    fn parse_with(mut parser: Self, string: &str) -> (Result<Expression, ParsingError>, usize) {
         let (tokens, size, error) = parser.parse(string);
         if let Some(error) = error {
             return (Err(error), size);
@@ -291,7 +335,8 @@ pub enum ParsingError {
     MismatchedBraces,
     MismatchedParentheses,
     MismatchedBrackets,
-    InvalidSeparatorToken
+    InvalidSeparatorToken,
+    InvalidConditional
 }
 
 impl Parser for ExpressionParser {
@@ -318,13 +363,16 @@ impl Parser for ExpressionParser {
         static ref INTEGER_REGEX: Regex = Regex::new(r"^\d+").unwrap();
         static ref NULL_REGEX: Regex = Regex::new(r"^(null)([^\w]+|$)").unwrap();
 
-        static ref CONTAINS_REGEX: Regex = Regex::new(r"(contains)[^\w]+").unwrap();
+        static ref CONTAINS_REGEX: Regex = Regex::new(r"^(contains)[^\w]+").unwrap();
         static ref IN_REGEX: Regex = Regex::new(r"^(in)[^\w]+").unwrap();
         static ref AND_REGEX: Regex = Regex::new(r"^(and)[^\w]+").unwrap();
         static ref OR_REGEX: Regex = Regex::new(r"^(or)[^\w]+").unwrap();
         static ref NOT_REGEX: Regex = Regex::new(r"^(not)[^\w]+").unwrap();
+        static ref AND_SYMBOL_REGEX: Regex = Regex::new(r"^&&").unwrap();
+        static ref OR_SYMBOL_REGEX: Regex = Regex::new(r"^\|\|").unwrap();
+        static ref NOT_SYMBOL_REGEX: Regex = Regex::new(r"^!").unwrap();
 
-        static ref FUNCTION_REGEX: Regex = Regex::new(r"^([a-zA-Z_]+)\s*\(").unwrap();
+        static ref FUNCTION_REGEX: Regex = Regex::new(r"^([a-zA-Z_]\w*)\s*\(").unwrap();
         static ref VARIABLE_REGEX: Regex = Regex::new(r"^([a-zA-Z_]\w*)").unwrap();
 
         static ref EQ_REGEX: Regex = Regex::new(r"^==").unwrap();
@@ -344,11 +392,17 @@ impl Parser for ExpressionParser {
 
         static ref DIV_REGEX: Regex = Regex::new(r"^/").unwrap();
         static ref COALESCE_REGEX: Regex = Regex::new(r"^\?\?").unwrap();
+        static ref QUESTION_REGEX: Regex = Regex::new(r"^\?").unwrap();
         static ref END_REGEX: Regex = Regex::new(r"^;").unwrap();
         }
 
         if let Some(whitespace) = WHITESPACE_REGEX.captures(&string) {
-            size += whitespace.get(0).unwrap().as_str().len();
+            let whitespace = whitespace.get(0).unwrap().as_str();
+            // Line expressions end at the end of the line unless a bracket is still open.
+            if self.ends_at_line && whitespace.contains('\n') && self.token_stack.iter().all(|token| token == &Question) && self.follows_value() {
+                return ParserResult::End(0);
+            }
+            size += whitespace.len();
         }
         self.size = size;
         let slice = &string[size..];
@@ -400,8 +454,13 @@ impl Parser for ExpressionParser {
             token = Some(Separator);
         }
         else if let Some(_) = self.get_token(&OBJECT_SEPARATOR_REGEX, slice) {
-            if let Some(ObjectStart) = self.token_stack.last() {
-                token = Some(ObjectSeparator);
+            match self.token_stack.last() {
+                Some(ObjectStart) => token = Some(ObjectSeparator),
+                Some(Question) => {
+                    self.token_stack.pop();
+                    token = Some(TernaryElse);
+                }
+                _ => {}
             }
         }
         else if let Some(s) = self.get_token(&BOOLEAN_REGEX, slice) {
@@ -439,7 +498,9 @@ impl Parser for ExpressionParser {
         else if let Some(_) = self.get_token(&NULL_REGEX, slice) {
             token = Some(Constant(Null));
         }
-        else if let Some(_) = self.get_token(&CONTAINS_REGEX, slice) {
+        // After a value contains is the operator, anywhere else it's the contains() function.
+        else if self.follows_value() && CONTAINS_REGEX.is_match(slice) {
+            self.get_token(&CONTAINS_REGEX, slice);
             token = Some(Operator(Operator::Contains));
         }
         else if let Some(_) = self.get_token(&IN_REGEX, slice) {
@@ -466,6 +527,16 @@ impl Parser for ExpressionParser {
         else if let Some(_) = self.get_token(&NEQ_REGEX, slice) {
             token = Some(Operator(Operator::NotEqual));
         }
+        else if self.get_token(&AND_SYMBOL_REGEX, slice).is_some() {
+            token = Some(Operator(Operator::And));
+        }
+        else if self.get_token(&OR_SYMBOL_REGEX, slice).is_some() {
+            token = Some(Operator(Operator::Or));
+        }
+        // Checked after !=
+        else if self.get_token(&NOT_SYMBOL_REGEX, slice).is_some() {
+            token = Some(UnaryOperator(UnaryOperator::Not));
+        }
         else if let Some(_) = self.get_token(&GTE_REGEX, slice) {
             token = Some(Operator(Operator::GreaterOrEqual));
         }        
@@ -497,28 +568,24 @@ impl Parser for ExpressionParser {
             token = Some(Operator(Operator::Div));
         }
         else if let Some(_) = self.get_token(&PLUS_REGEX, slice) {
-            token = match self.last {
-                None |
-                Some(LeftParen) |
-                Some(ArrayStart) |
-                Some(Operator(_)) |
-                Some(UnaryOperator(_)) => Some(UnaryOperator(UnaryOperator::Plus)),
-                _ => Some(Operator(Operator::Add))
+            token = match self.follows_value() {
+                true => Some(Operator(Operator::Add)),
+                false => Some(UnaryOperator(UnaryOperator::Plus))
             };
         }
         else if let Some(_) = self.get_token(&MINUS_REGEX, slice) {
-            token = match self.last {
-                None | 
-                Some(LeftParen) |
-                Some(ArrayStart) |
-                Some(Operator(_)) |
-                Some(UnaryOperator(_)) => Some(UnaryOperator(UnaryOperator::Minus)),
-                _ => Some(Operator(Operator::Sub))
+            token = match self.follows_value() {
+                true => Some(Operator(Operator::Sub)),
+                false => Some(UnaryOperator(UnaryOperator::Minus))
             };
         }
         else if let Some(_) = self.get_token(&COALESCE_REGEX, slice) {
             token = Some(Operator(Operator::Coalesce));
         }
+        else if self.get_token(&QUESTION_REGEX, slice).is_some() {
+            self.token_stack.push(Question);
+            token = Some(Question);
+        }
         else if let Some(_) = self.get_token(&END_REGEX, slice) {
             return ParserResult::End(self.size);
         }
@@ -534,3 +601,106 @@ impl Parser for ExpressionParser {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression::StateManager;
+
+    struct NoVariables;
+
+    impl StateManager for NoVariables {
+        fn get(&self, _variable: &str) -> Option<&Value> {
+            None
+        }
+    }
+
+    fn eval(source: &str) -> Value {
+        match ExpressionParser::parse(source) {
+            (Ok(expression), size) if size == source.len() => expression.eval(&NoVariables),
+            _ => panic!("'{}' doesn't parse", source)
+        }
+    }
+
+    // a and b as variables, to compare expressions for every combination of them.
+    struct Flags(Value, Value);
+
+    impl StateManager for Flags {
+        fn get(&self, variable: &str) -> Option<&Value> {
+            match variable {
+                "a" => Some(&self.0),
+                "b" => Some(&self.1),
+                _ => None
+            }
+        }
+    }
+
+    fn assert_same_as_compact(source: &str, compact: &str) {
+        for (a, b) in [(true, true), (true, false), (false, true), (false, false)] {
+            let flags = Flags(Boolean(a), Boolean(b));
+            let eval = |source: &str| match ExpressionParser::parse(source) {
+                (Ok(expression), size) if size == source.len() => expression.eval(&flags),
+                _ => panic!("'{}' doesn't parse", source)
+            };
+            assert_eq!(eval(source), eval(compact), "'{}' with a = {}, b = {}", source, a, b);
+        }
+    }
+
+    #[test]
+    fn spaces_and_tabs_are_insignificant() {
+        assert_same_as_compact("a   and\tb", "a and b");
+        assert_same_as_compact("not\t\ta  or   b", "not a or b");
+        assert_same_as_compact("( a\tand b )  ==  ( b and a )", "(a and b) == (b and a)");
+    }
+
+    #[test]
+    fn conditions_can_span_lines() {
+        assert_same_as_compact("(a and\n  not b) or b", "(a and not b) or b");
+        // What is left of a and @@ note, once the comment is removed from the line.
+        assert_same_as_compact("a and\n b", "a and b");
+    }
+
+    #[test]
+    fn whitespace_in_strings_is_kept() {
+        assert_eq!(eval("\"a   \tb\"  +  \" c\""), Text("a   \tb c".to_string()));
+    }
+
+    #[test]
+    fn conditional_picks_a_branch() {
+        assert_eq!(eval("1 > 0 ? \"pos\" : \"neg\""), Text("pos".to_string()));
+        assert_eq!(eval("-1 > 0 ? \"pos\" : \"neg\""), Text("neg".to_string()));
+    }
+
+    #[test]
+    fn nested_conditionals_group_to_the_right() {
+        assert_eq!(eval("false ? 1 : true ? 2 : 3"), Integer(2));
+        assert_eq!(eval("false ? 1 : false ? 2 : 3"), Integer(3));
+        assert_eq!(eval("true ? false ? 1 : 2 : 3"), Integer(2));
+    }
+
+    #[test]
+    fn conditional_has_the_lowest_precedence() {
+        // The condition takes everything before the ?, including ?? and or.
+        assert_eq!(eval("null ?? false ? 1 : 2"), Integer(2));
+        assert_eq!(eval("null ?? true ? 1 : 2"), Integer(1));
+        assert_eq!(eval("false or true ? 1 : 2"), Integer(1));
+        assert_eq!(eval("true and false ? 1 : 2"), Integer(2));
+        // A branch takes everything after the : too.
+        assert_eq!(eval("true ? null ?? 3 : 4"), Integer(3));
+        assert_eq!(eval("false ? 1 : 2 + 10"), Integer(12));
+        assert_eq!(eval("(true ? 1 : 2) + 10"), Integer(11));
+    }
+
+    #[test]
+    fn conditional_inside_brackets() {
+        assert_eq!(eval("[true ? 1 : 2, 3]"), Array(vec![Integer(1), Integer(3)]));
+        assert_eq!(eval("{\"k\": false ? 1 : 2}.k"), Integer(2));
+        assert_eq!(eval("max(false ? 1 : 5, 2)"), Integer(5));
+        assert_eq!(eval("[1, 2][true ? 0 : 1]"), Integer(1));
+    }
+
+    #[test]
+    fn incomplete_conditional_is_an_error() {
+        assert!(ExpressionParser::parse("true ? 1").0.is_err());
+    }
+}
@@ -0,0 +1,212 @@
+// C bindings, built with the "ffi" feature.
+//
+// Ownership: every pointer returned by a lift_* function belongs to the caller.
+// Stories are freed with lift_story_free, unless passed to lift_interpreter_new, which takes them.
+// Interpreters are freed with lift_interpreter_free, strings with lift_string_free.
+// Null pointers are accepted everywhere and treated as missing values.
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use crate::story::{Interpreter, Story};
+use crate::value::Value;
+
+unsafe fn read_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Strings with interior nul bytes can't be passed to C.
+    CString::new(s).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Parses a story, returns null on failure.
+/// If error is not null, it's set to the error message on failure and to null otherwise.
+///
+/// # Safety
+/// `source` must be null or a valid nul-terminated string. `error` must be null or point to writable
+/// memory for a pointer. The returned story and error message belong to the caller, free them with
+/// lift_story_free and lift_string_free.
+#[no_mangle]
+pub unsafe extern "C" fn lift_story_new(source: *const c_char, error: *mut *mut c_char) -> *mut Story {
+    if !error.is_null() {
+        *error = ptr::null_mut();
+    }
+    let message = match read_str(source).map(Story::new) {
+        Some(Ok(story)) => return Box::into_raw(Box::new(story)),
+        Some(Err(story_error)) => story_error.to_string(),
+        None => "Story source is null or not valid UTF-8".to_string()
+    };
+    if !error.is_null() {
+        *error = into_c_string(message);
+    }
+    ptr::null_mut()
+}
+
+/// Frees a story that wasn't passed to lift_interpreter_new.
+///
+/// # Safety
+/// `story` must be null or a pointer returned by lift_story_new that hasn't been freed or passed to
+/// lift_interpreter_new. It must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lift_story_free(story: *mut Story) {
+    if !story.is_null() {
+        drop(Box::from_raw(story));
+    }
+}
+
+/// Takes ownership of the story, which must not be used or freed afterwards.
+/// Returns null if the story is null.
+///
+/// # Safety
+/// `story` must be null or a pointer returned by lift_story_new that hasn't been freed or passed to
+/// lift_interpreter_new before. The returned interpreter belongs to the caller, free it with
+/// lift_interpreter_free.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_new(story: *mut Story) -> *mut Interpreter {
+    if story.is_null() {
+        return ptr::null_mut();
+    }
+    let story = Box::from_raw(story);
+    Box::into_raw(Box::new(Interpreter::new(*story)))
+}
+
+/// Frees an interpreter along with its story.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed.
+/// It must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_free(interpreter: *mut Interpreter) {
+    if !interpreter.is_null() {
+        drop(Box::from_raw(interpreter));
+    }
+}
+
+/// Plays the story from the current page, does nothing if the interpreter is null.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_play(interpreter: *mut Interpreter) {
+    if let Some(interpreter) = interpreter.as_mut() {
+        interpreter.play();
+    }
+}
+
+/// The value is plain JSON, a null value sends null. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+/// `value` must be null or a valid nul-terminated string, it's only read during the call.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_send(interpreter: *mut Interpreter, index: usize, value: *const c_char) -> c_int {
+    let interpreter = match interpreter.as_mut() {
+        Some(interpreter) => interpreter,
+        None => return -1
+    };
+    let value = match read_str(value).map(serde_json::from_str::<serde_json::Value>) {
+        None => Value::Null,
+        Some(Ok(json)) => Value::from_json(json),
+        Some(Err(_)) => return -1
+    };
+    match interpreter.send(index, value) {
+        Ok(()) => 0,
+        Err(_) => -1
+    }
+}
+
+/// Number of elements in the output, 0 if the interpreter is null.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_output_len(interpreter: *const Interpreter) -> usize {
+    interpreter.as_ref().map_or(0, |interpreter| interpreter.output().len())
+}
+
+/// 1 if the story reached an ending and has no choices left, 0 otherwise.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_is_finished(interpreter: *const Interpreter) -> c_int {
+    interpreter.as_ref().map_or(0, |interpreter| interpreter.is_finished() as c_int)
+}
+
+/// An output element as JSON, null if the index is out of range.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+/// The returned string belongs to the caller, free it with lift_string_free.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_element(interpreter: *const Interpreter, index: usize) -> *mut c_char {
+    match interpreter.as_ref().and_then(|interpreter| interpreter.output_element(index)) {
+        Some(element) => serde_json::to_string(&element).map_or(ptr::null_mut(), into_c_string),
+        None => ptr::null_mut()
+    }
+}
+
+/// The whole output as a JSON array, null if the interpreter is null.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+/// The returned string belongs to the caller, free it with lift_string_free.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_output(interpreter: *const Interpreter) -> *mut c_char {
+    match interpreter.as_ref() {
+        Some(interpreter) => serde_json::to_string(&interpreter.output_elements()).map_or(ptr::null_mut(), into_c_string),
+        None => ptr::null_mut()
+    }
+}
+
+/// The state as JSON, for lift_interpreter_load_state. Null if the interpreter is null.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+/// The returned string belongs to the caller, free it with lift_string_free.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_dump_state(interpreter: *const Interpreter) -> *mut c_char {
+    match interpreter.as_ref().and_then(|interpreter| interpreter.dump_state()) {
+        Some(json) => into_c_string(json),
+        None => ptr::null_mut()
+    }
+}
+
+/// Loads a state made by lift_interpreter_dump_state. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+/// `interpreter` must be null or a pointer returned by lift_interpreter_new that hasn't been freed,
+/// and it must not be used from another thread during the call.
+/// `json` must be null or a valid nul-terminated string, it's only read during the call.
+#[no_mangle]
+pub unsafe extern "C" fn lift_interpreter_load_state(interpreter: *mut Interpreter, json: *const c_char) -> c_int {
+    match (interpreter.as_mut(), read_str(json)) {
+        (Some(interpreter), Some(json)) => match interpreter.load_state(json) {
+            Ok(()) => 0,
+            Err(_) => -1
+        },
+        _ => -1
+    }
+}
+
+/// Frees a string returned by any lift_* function.
+///
+/// # Safety
+/// `s` must be null or a string returned by a lift_* function that hasn't been freed. Strings made
+/// by other allocators, such as malloc, must not be passed. It must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lift_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
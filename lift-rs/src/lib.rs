@@ -4,6 +4,20 @@ mod expression;
 mod expression_parser;
 mod content;
 mod story;
+mod render;
+mod markup;
+mod compact;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 
-pub use story::{Interpreter, Element, Story};
-pub use value::Value;
+pub use story::{Interpreter, Element, OutputElement, OutputKind, Story, StoryError, StoryConfig, StoryMeta, ValidationWarning, Reachability, Coverage, Edge, SendError, LoadError, CompileError, TraceEvent, RuntimeWarning, AssertionFailure, ExecMetrics, StateSnapshot, StateDiff, VariableChange, PageHook, AutosaveHook};
+pub use value::{Value, ValueFormat};
+pub use expression::HostFunction;
+pub use content::{InputSpec, InputType, Page};
+pub use markup::Span;
+pub use parser::ContentError;
+pub use render::{Renderer, PlainTextRenderer, HtmlRenderer, TerminalRenderer};
+#[cfg(feature = "wasm")]
+pub use wasm::WasmInterpreter;
@@ -3,14 +3,9 @@ use std::fs;
 use std::process;
 use std::env;
 use std::io;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 
-enum UserActions {
-    Tap(usize),
-    Prompt(usize)
-}
-
-fn prompt(interpreter: &mut Interpreter, choices: Vec<UserActions>) {
+fn prompt(interpreter: &mut Interpreter, choices: Vec<usize>) {
     print!(" -> ");
     let _ = io::stdout().flush();
     let mut input = String::new();
@@ -22,6 +17,11 @@ fn prompt(interpreter: &mut Interpreter, choices: Vec<UserActions>) {
         "help" | "h" | "?" => print_help(),
         "load" => load_state(interpreter),
         "save" | "s" => save_state(interpreter),
+        "back" | "b" => {
+            if !interpreter.back() {
+                println!("Nothing to undo.");
+            }
+        }
         _ => {
             if let Ok(choice) = input.trim().parse::<u32>() {
                 handle_choice(interpreter, choices, choice)
@@ -35,10 +35,11 @@ fn prompt(interpreter: &mut Interpreter, choices: Vec<UserActions>) {
 fn load_state(interpreter: &mut Interpreter) {
     let file = "lift_state.json";
     if let Ok(json) = fs::read_to_string(file) {
-        if let Ok(_) = interpreter.load_state(&json) {
-            println!("Loading state...");
-            return;
+        match interpreter.load_state(&json) {
+            Ok(_) => println!("Loading state..."),
+            Err(error) => eprintln!("Error while loading '{}': {}", file, error)
         }
+        return;
     }
     eprintln!("Error while reading file '{}'", file);
 }
@@ -62,36 +63,60 @@ fn print_help() {
         "Command Reference:\n",
         "  quit | exit | q   Quit Lift\n",
         "  save | s          Save state to file\n",
+        "  back | b          Undo the last action\n",
         "  help | h | ?      Display this help message\n",
         "  [N]               Execute action N"
     ));
 }
 
-fn handle_choice(interpreter: &mut Interpreter, choices: Vec<UserActions>, choice: u32) {
-  if let Some(action) = choices.get((choice - 1) as usize) {
-       match action {
-           UserActions::Tap(i) => interpreter.send(*i, Value::Null),
-           UserActions::Prompt(i) => {
-               let mut user_input = String::new();
-               print!(" -> ");
-               let _ = io::stdout().flush();
-               io::stdin()
-                   .read_line(&mut user_input)
-                   .expect("Failed to read input");
-               user_input = user_input.trim().to_string();
-               interpreter.send(*i, Value::Text(user_input));
-           }
-       }
-   }
-   else {
-       println!("Invalid choice.");
-   }
+fn read_value(label: &str) -> Value {
+    print!(" {} -> ", label);
+    let _ = io::stdout().flush();
+    let mut user_input = String::new();
+    io::stdin()
+        .read_line(&mut user_input)
+        .expect("Failed to read input");
+    Value::Text(user_input.trim().to_string())
+}
+
+fn handle_choice(interpreter: &mut Interpreter, choices: Vec<usize>, choice: u32) {
+    if let Some(index) = (choice as usize).checked_sub(1).and_then(|i| choices.get(i)) {
+        let result = if let Some(Element::Form { fields, .. }) = interpreter.output().get(*index) {
+            let labels: Vec<(String, String)> = fields.iter()
+                .map(|(variable, spec)| (variable.to_string(), spec.prompt.clone().unwrap_or(variable.to_string())))
+                .collect();
+            let values = labels.into_iter().map(|(variable, label)| (variable, read_value(&label))).collect();
+            interpreter.submit(*index, values)
+        }
+        else if let Some(Element::Input(..)) = interpreter.output().get(*index) {
+            let mut user_input = String::new();
+            print!(" -> ");
+            let _ = io::stdout().flush();
+            io::stdin()
+                .read_line(&mut user_input)
+                .expect("Failed to read input");
+            user_input = user_input.trim().to_string();
+            interpreter.send(*index, Value::Text(user_input))
+        }
+        else {
+            interpreter.send(*index, Value::Null)
+        };
+        if let Err(error) = result {
+            println!("{}", error);
+        }
+    }
+    else {
+        println!("Invalid choice.");
+    }
 }
 
 fn play(interpreter: &mut Interpreter) {
+    let renderer = TerminalRenderer {
+        no_color: !io::stdout().is_terminal() || env::var_os("NO_COLOR").is_some()
+    };
     interpreter.play();
     loop {
-        let (content, choices) = render(interpreter);
+        let (content, choices) = renderer.render(interpreter.output());
         println!("--- --- --- ---");
         println!("{}", content);
         println!("--- --- --- ---\n");
@@ -99,70 +124,6 @@ fn play(interpreter: &mut Interpreter) {
     }
 }
 
-enum LastElement {
-    Empty,
-    Text,
-    Break
-}
-
-use LastElement::*;
-
-fn render(interpreter: &Interpreter) -> (String, Vec<UserActions>) {
-    let mut ret = String::new();
-    let mut input_id = 1;
-    let mut choices = Vec::<UserActions>::new();
-    let mut last = Empty;
-    for (index, element) in interpreter.output().iter().enumerate() {
-        match element {
-            Element::Text(text) => {
-                match (&last, !text.is_empty()) {
-                    (Empty, true) => {
-                        ret += text;
-                        last = Text;
-                    }
-                    (Text, true) => {
-                        ret += "\n";
-                        ret += text;
-                    }
-                    (Text, false) => last = Break,
-                    (Break, true) => {
-                            ret += "\n\n";
-                            ret += text;
-                            last = Text;
-                    }
-                    _ => {}
-                }
-            }
-            Element::Link(title, _)
-            | Element::ContentLink(title, _)
-            | Element::JumpLink(title, _, _) => {
-                match last {
-                    Text => ret += "\n",
-                    Break => ret += "\n\n",
-                    _ => {}
-                };
-                last = Text;
-                ret += &format!("{}. [{}]", input_id, title);
-                input_id += 1;
-                choices.push(UserActions::Tap(index));
-            }
-            Element::Input(_, _) => {
-                match last {
-                    Text => ret += "\n",
-                    Break => ret += "\n\n",
-                    _ => {}
-                };
-                last = Text;
-                ret += &format!("{}. [__________]", input_id);
-                input_id += 1;
-                choices.push(UserActions::Prompt(index));
-            }
-            Element::Error(e) => ret += &format!("ERROR: {}\n", e),
-        }
-    }
-    return (ret, choices);
-}
-
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
@@ -178,16 +139,17 @@ fn main() {
 }
 
 fn create_story(files: &[String]) -> Story {
-    let mut source = String::new();
+    let mut sources = Vec::<(&str, String)>::new();
     for file in files {
         if let Ok(content) = fs::read_to_string(file) {
-            source.push_str(&content);
+            sources.push((file, content));
         } else {
             eprintln!("Error while reading file '{}'", file);
             process::exit(0);
         }
     }
-    match Story::new(&source) {
+    let sources: Vec<(&str, &str)> = sources.iter().map(|(file, source)| (*file, source.as_str())).collect();
+    match Story::from_sources(&sources) {
         Ok(story) => return story,
         Err(error) => {
             eprintln!("{}", error);
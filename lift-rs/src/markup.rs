@@ -0,0 +1,117 @@
+// Inline markup: *italic*, **bold** and `code`
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub text: String,
+    #[serde(default)]
+    pub bold: bool,
+    #[serde(default)]
+    pub italic: bool,
+    #[serde(default)]
+    pub code: bool
+}
+
+impl Span {
+    pub fn is_plain(&self) -> bool {
+        !self.bold && !self.italic && !self.code
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Marker {
+    Bold,
+    Italic,
+    Code
+}
+
+impl Marker {
+    fn len(&self) -> usize {
+        match self {
+            Marker::Bold => 2,
+            Marker::Italic | Marker::Code => 1
+        }
+    }
+}
+
+// Splits text into styled spans. Only characters where markup[i] is true can be markers,
+// so text coming from variables is never styled. A marker opens before a non-space
+// character and closes after one, markers that aren't closed are kept as text.
+pub fn spans(text: &str, markup: &[bool]) -> Vec<Span> {
+    // Most lines have no markers at all.
+    let has_markers = text.chars().zip(markup).any(|(c, is_markup)| *is_markup && matches!(c, '*' | '`'));
+    if !has_markers {
+        return vec![Span { text: text.to_string(), ..Span::default() }];
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let is_space = |i: usize| chars.get(i).is_none_or(|c| c.is_whitespace());
+    let mut markers: Vec<(usize, Marker)> = vec![];
+    let mut open: Vec<usize> = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let closes_italic = open.last().is_some_and(|index| markers[*index].1 == Marker::Italic) && !is_space(i.wrapping_sub(1));
+        let marker = match chars[i] {
+            _ if !markup.get(i).copied().unwrap_or(false) => None,
+            '`' => Some(Marker::Code),
+            // In ***text*** the italic marker opened last is closed first.
+            '*' if closes_italic => Some(Marker::Italic),
+            '*' if chars.get(i + 1) == Some(&'*') && markup.get(i + 1).copied().unwrap_or(false) => Some(Marker::Bold),
+            '*' => Some(Marker::Italic),
+            _ => None
+        };
+        let marker = match marker {
+            Some(marker) => marker,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        let in_code = open.last().is_some_and(|index| markers[*index].1 == Marker::Code);
+        let opened = open.iter().rposition(|index| markers[*index].1 == marker);
+        match opened {
+            Some(position) if !is_space(i.wrapping_sub(1)) && (!in_code || marker == Marker::Code) => {
+                // Markers opened inside the closed one can no longer be closed.
+                for index in open.drain(position..).skip(1) {
+                    markers[index].0 = usize::MAX;
+                }
+                markers.push((i, marker));
+            }
+            None if !in_code && !is_space(i + marker.len()) => {
+                open.push(markers.len());
+                markers.push((i, marker));
+            }
+            _ => {}
+        }
+        i += marker.len();
+    }
+    for index in open {
+        markers[index].0 = usize::MAX;
+    }
+    markers.retain(|(position, _)| *position != usize::MAX);
+
+    let mut spans: Vec<Span> = vec![];
+    let mut current = Span::default();
+    let mut markers = markers.into_iter().peekable();
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some((_, marker)) = markers.next_if(|(position, _)| *position == i) {
+            if !current.text.is_empty() {
+                spans.push(current.clone());
+                current.text.clear();
+            }
+            match marker {
+                Marker::Bold => current.bold = !current.bold,
+                Marker::Italic => current.italic = !current.italic,
+                Marker::Code => current.code = !current.code
+            }
+            i += marker.len();
+            continue;
+        }
+        current.text.push(chars[i]);
+        i += 1;
+    }
+    if !current.text.is_empty() || spans.is_empty() {
+        spans.push(current);
+    }
+    spans
+}
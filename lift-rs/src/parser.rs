@@ -47,7 +47,7 @@ impl TextParser {
         if !trimmed.is_empty() {
             elements.push(TextElement::Text(trimmed.to_string()));
         }
-        return ParserResult::Some(TextContent{elements}, size);
+        return ParserResult::Some(TextContent::new(elements), size);
     }
 
     fn literal_or_none(elements: Vec<TextElement>, literal: String, size: usize)
@@ -90,8 +90,16 @@ impl Parser for TextParser {
                     Some('n') => Some('\n'),
                     Some(c) => Some(c)
                 };
+                // Escaped characters are kept apart from the text, so they are never read as markup.
                 if let Some(replace_char) = replace {
-                    literal.push(replace_char);
+                    if !literal.is_empty() {
+                        elements.push(TextElement::Text(literal));
+                        literal = "".to_string();
+                    }
+                    match elements.last_mut() {
+                        Some(TextElement::Escaped(escaped)) => escaped.push(replace_char),
+                        _ => elements.push(TextElement::Escaped(replace_char.to_string()))
+                    }
                     chars.next();
                     consumed_size += replace_char.len_utf8();
                 } else {
@@ -120,16 +128,22 @@ impl Parser for TextParser {
                     let expression_string = chars.as_str();
                     let (expression, len) = ExpressionParser::parse(expression_string);
                     let rest = chars.as_str()[len..].trim_start();
-                    if rest.starts_with("}") {
-                        if let Ok(expr) = expression {
+                    let line = expression_string.split('\n').next().unwrap();
+                    match (expression, rest.starts_with("}")) {
+                        (Ok(expr), true) => {
                             let size = '}'.len_utf8() + (expression_string.len() - rest.len());
                             chars = rest['}'.len_utf8()..].chars();
                             consumed_size += size;
                             elements.push(TextElement::Expression(expr));
                         }
-                    }
-                    else {
-                        literal.push_str("${");
+                        // Invalid expressions are shown as an inline error marker instead of their source
+                        _ => if let Some(end) = line.find('}') {
+                            elements.push(TextElement::Error(line[..end].trim().to_string()));
+                            chars = expression_string[end + '}'.len_utf8()..].chars();
+                            consumed_size += end + '}'.len_utf8();
+                        } else {
+                            literal.push_str("${");
+                        }
                     }
                 } else {
                     literal.push('$');
@@ -161,17 +175,68 @@ impl Parser for TextParser {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum ContentError {
     InvalidCommand(String),
     InvalidParameters(String),
+    // A @link, @once or @glossary without the -> before its destination.
+    MissingArrow(String),
+    // The expression of a command can't be parsed. Offset is where the expression starts, in bytes
+    // from the start of the error's position, so the reported column points at the expression.
+    InvalidExpression { command: String, expression: String, offset: usize },
     MissingClosingBrace
 }
 
+impl ContentError {
+    pub fn offset(&self) -> usize {
+        match self {
+            ContentError::InvalidExpression { offset, .. } => *offset,
+            _ => 0
+        }
+    }
+
+    // Finds what is wrong with the arguments of a command that didn't match any of its forms.
+    fn diagnose(command: &str, arguments: &str, position: usize) -> Self {
+        let line = arguments.split('\n').next().unwrap_or("").trim_end();
+        let has_block = line.ends_with('{');
+        match command {
+            "glossary" if !line.contains("->") => return ContentError::MissingArrow(command.to_string()),
+            "link" | "once" if !line.contains("->") && !has_block => return ContentError::MissingArrow(command.to_string()),
+            _ => {}
+        }
+        let body = line.strip_suffix('{').unwrap_or(line);
+        let start = match command {
+            "if" | "elseif" | "while" | "switch" | "case" | "return" | "wait" | "assert" => Some(0),
+            "set" | "setlocal" | "let" | "const" | "default" => body.find('=').map(|index| index + '='.len_utf8()),
+            "for" => body.find(" in ").map(|index| index + " in ".len()),
+            _ => None
+        };
+        if let Some(start) = start {
+            let source = body[start..].trim();
+            match ExpressionParser::parse(source) {
+                (Ok(expression), size) if size == source.len() && expression.is_complete() => {}
+                _ => {
+                    let leading = body[start..].len() - body[start..].trim_start().len();
+                    return ContentError::InvalidExpression {
+                        command: command.to_string(),
+                        expression: source.to_string(),
+                        offset: position + start + leading
+                    };
+                }
+            }
+        }
+        ContentError::InvalidParameters(command.to_string())
+    }
+}
+
 impl fmt::Display for ContentError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ContentError::InvalidCommand(cmd) => write!(f, "Invalid command: @{}", cmd),
             ContentError::InvalidParameters(cmd) => write!(f, "Invalid parameters for command: @{}", cmd),
+            ContentError::MissingArrow(cmd) => write!(f, "Missing -> before the destination of @{}", cmd),
+            ContentError::InvalidExpression { command, expression, .. } =>
+                write!(f, "Invalid expression in @{}: {}", command, expression),
             ContentError::MissingClosingBrace => write!(f, "Missing closing brace")
         }
     }
@@ -199,17 +264,11 @@ impl Parser for ContentParser {
     
     fn next(&mut self, string: &str) -> ParserResult<Self::Token, Self::Error, usize> {
         lazy_static! {
-            static ref COMMENT_REGEX: Regex = Regex::new(r"^@@.*\n").unwrap();
             static ref COMMAND_REGEX: Regex = Regex::new(r"^@(?P<name>[a-z_]+)").unwrap();
             static ref COMMAND_END_REGEX: Regex = Regex::new(r"^[^\S\n]*(\n|)").unwrap();
         }
         let mut slice = string;
 
-        if let Some(capture) = COMMENT_REGEX.captures(slice) {
-            let comment = capture.get(0).unwrap().as_str();
-            slice = &slice[comment.len()..];
-        }
-
         let expected_token = match self.capture_level {
             0 => "".to_string(),
             _ => "}".to_string()
@@ -229,25 +288,52 @@ impl Parser for ContentParser {
             let command_name = capture.name("name").unwrap().as_str();
             let command_size = capture.get(0).unwrap().as_str().len();
             slice = &slice[command_size..];
+            let arguments = slice;
 
             let expect: Vec<Expect> = match command_name {
-                "link" => vec![
+                "link" | "once" => vec![
                     Expect::Or(vec![
+                        vec![Expect::Text, Expect::string("->"), Expect::Text, Expect::inline("|"), Expect::Text, Expect::inline("@if"), Expect::Expression, Expect::Block],
+                        vec![Expect::Text, Expect::string("->"), Expect::Text, Expect::inline("|"), Expect::Text, Expect::Block],
+                        vec![Expect::Text, Expect::string("->"), Expect::Text, Expect::inline("@if"), Expect::Expression, Expect::Block],
+                        vec![Expect::Text, Expect::string("->"), Expect::Text, Expect::inline("@if"), Expect::Expression],
                         vec![Expect::Text, Expect::string("->"), Expect::Text, Expect::Block],
                         vec![Expect::Text, Expect::string("->"), Expect::Text],
+                        vec![Expect::Text, Expect::inline("@if"), Expect::Expression, Expect::Block],
                         vec![Expect::Text, Expect::Block]
                     ])
                 ],
+                "glossary" => vec![
+                    Expect::Text, Expect::string("->"), Expect::Text
+                ],
                 "input" => vec![
-                    Expect::Variable, Expect::Block
+                    Expect::Variable,
+                    Expect::Or(vec![
+                        vec![Expect::inline("as"), Expect::Variable, Expect::named(&["min", "max", "pattern", "prompt", "placeholder"])],
+                        vec![Expect::named(&["prompt", "placeholder"])]
+                    ]),
+                    Expect::Block
+                ],
+                "field" => vec![
+                    Expect::Variable,
+                    Expect::Or(vec![
+                        vec![Expect::inline("as"), Expect::Variable, Expect::named(&["min", "max", "pattern", "prompt", "placeholder"])],
+                        vec![Expect::named(&["prompt", "placeholder"])]
+                    ])
+                ],
+                "submit" => vec![
+                    Expect::Text, Expect::Block
                 ],
                 "set" | "setlocal" => vec![
                     Expect::Indices, Expect::string("="), Expect::Expression
                 ],
+                "let" | "const" | "default" => vec![
+                    Expect::Variable, Expect::string("="), Expect::Expression
+                ],
                 "if" | "elseif" => vec![
                     Expect::Expression, Expect::Block
                 ],
-                "else" => vec![
+                "else" | "setup" => vec![
                     Expect::Block
                 ],
                 "for" => vec![
@@ -257,32 +343,72 @@ impl Parser for ContentParser {
                     ]),
                     Expect::string("in"), Expect::Expression, Expect::Block
                 ],
-                "while" => vec![
+                "while" | "switch" | "case" => vec![
                     Expect::Expression, Expect::Block
                 ],
-                "goto" | "import" => vec![
+                "random" => vec![
+                    Expect::Block
+                ],
+                "option" => vec![
+                    Expect::Or(vec![
+                        vec![Expect::Block],
+                        vec![Expect::Expression, Expect::Block]
+                    ])
+                ],
+                "goto" => vec![
                     Expect::Text
                 ],
+                "wait" => vec![
+                    Expect::Expression
+                ],
+                "assert" => vec![
+                    Expect::Expression, Expect::named(&["message"])
+                ],
+                "import" | "use" => vec![
+                    Expect::Or(vec![
+                        vec![Expect::Text, Expect::Arguments, Expect::inline("->"), Expect::Variable],
+                        vec![Expect::Text, Expect::Arguments],
+                        vec![Expect::Text, Expect::string("->"), Expect::Variable],
+                        vec![Expect::Text]
+                    ])
+                ],
+                "audio" => vec![
+                    Expect::Or(vec![
+                        vec![Expect::Text, Expect::Arguments],
+                        vec![Expect::Text]
+                    ])
+                ],
+                "return" => vec![
+                    Expect::Or(vec![
+                        vec![Expect::LineExpression],
+                        vec![]
+                    ])
+                ],
+                "break" | "continue" | "clear" => vec![],
+                "label" => vec![
+                    Expect::Or(vec![
+                        vec![Expect::string("("), Expect::Variable, Expect::string(")")],
+                        vec![Expect::Variable]
+                    ])
+                ],
                 _ => return ParserResult::Error(Self::Error::InvalidCommand(command_name.to_string()))
             };
             if let Some(params) = Params::expect(&mut slice, &expect, self.capture_level) {
                 if let Some(Params::Block) = params.last() {
                     self.capture_level += 1;
                 }
-                match params.last() {
-                    Some(Params::Text(_)) => {}
-                    _ => {
-                        if let Some(capture) = COMMAND_END_REGEX.captures(slice) {
-                            let size = capture.get(0).unwrap().as_str().len();
-                            slice = &slice[size..];
-                        }
+                // Text parameters already consume the end of the line.
+                if !string[..string.len() - slice.len()].ends_with('\n') {
+                    if let Some(capture) = COMMAND_END_REGEX.captures(slice) {
+                        let size = capture.get(0).unwrap().as_str().len();
+                        slice = &slice[size..];
                     }
-                };
+                }
                 let final_size = string.len() - slice.len();
                 return ParserResult::Some(Self::Token::Command(command_name.to_string(), params), final_size);
             }
             else {
-                return ParserResult::Error(Self::Error::InvalidParameters(command_name.to_string()))
+                return ParserResult::Error(Self::Error::diagnose(command_name, arguments, string.len() - arguments.len()))
             }
         }
 
@@ -305,8 +431,12 @@ enum Expect {
     Variable,
     Indices,
     String(String),
+    Inline(String), // A string that must be on the same line as the previous parameter
+    Named(Vec<String>), // Any number of `name expression` pairs on the same line
+    Arguments, // (name = expression, ...)
     Or(Vec<Vec<Expect>>),
     Expression,
+    LineExpression, // An expression on the same line as the command, ending with it
     Block
 }
 
@@ -314,6 +444,14 @@ impl Expect {
     fn string(string: &str) -> Self {
         Self::String(string.to_string())
     }
+
+    fn inline(string: &str) -> Self {
+        Self::Inline(string.to_string())
+    }
+
+    fn named(names: &[&str]) -> Self {
+        Self::Named(names.iter().map(|name| name.to_string()).collect())
+    }
 }
 
 
@@ -322,6 +460,7 @@ pub enum Params {
     Variable(String),
     Indices(String, Vec<Expression>),
     Expression(Expression),
+    Named(String, Expression),
     Block
 }
 
@@ -331,8 +470,11 @@ impl Params {
             static ref VARIABLE_REGEX: Regex = Regex::new(r"^(?P<variable>[a-zA-Z_]\w*)").unwrap();
         }
         let mut response = Vec::<Params>::new();
+        let mut new_line = false;
         for (index, param) in parameters.iter().enumerate() {
+            let untrimmed = *slice;
             *slice = &slice.trim_start();
+            new_line |= untrimmed[..untrimmed.len() - slice.len()].contains('\n');
             match param {
                 Expect::Or(params_list) => {
                     let mut complete = false;
@@ -350,13 +492,15 @@ impl Params {
                 Expect::Text => {
                     let expects = match (parameters.get(index + 1), capture_level) {
                         (Some(Expect::Block), _) => "{",
-                        (Some(Expect::String(s)), _) => s,
+                        (Some(Expect::String(s)), _) | (Some(Expect::Inline(s)), _) => s,
+                        (Some(Expect::Arguments), _) => "(",
                         (_, 0) => "",
                         (_, _) => "}"
                     };
 
                     let mut parser = TextParser { expects: expects.to_string() };
                     if let ParserResult::Some(content, size) = parser.next(slice) {
+                        new_line = slice[..size].ends_with('\n');
                         *slice = &slice[size..];
                         response.push(Params::Text(content));
                     }
@@ -382,6 +526,12 @@ impl Params {
                     } 
                     else { return None }
                 }
+                Expect::Inline(s) => {
+                    if !new_line && slice.starts_with(s) {
+                        *slice = &slice[s.len()..];
+                    }
+                    else { return None }
+                }
                 Expect::Block => {
                     if slice.starts_with("{") {
                         response.push(Params::Block);
@@ -396,8 +546,87 @@ impl Params {
                     }
                     else { return None }
                 }
+                Expect::LineExpression => {
+                    if new_line { return None }
+                    match ExpressionParser::parse_line(slice) {
+                        (Ok(expression), size) => {
+                            response.push(Params::Expression(expression));
+                            *slice = &slice[size..];
+                        }
+                        _ => return None
+                    }
+                }
+                Expect::Arguments => {
+                    *slice = slice.strip_prefix('(')?;
+                    loop {
+                        *slice = slice.trim_start_matches([' ', '\t']);
+                        if let Some(rest) = slice.strip_prefix(')') {
+                            *slice = rest;
+                            break;
+                        }
+                        let capture = VARIABLE_REGEX.captures(slice)?;
+                        let name = capture.name("variable").unwrap().as_str();
+                        let rest = slice[name.len()..].trim_start_matches([' ', '\t']).strip_prefix('=')?;
+                        let end = Self::argument_end(rest)?;
+                        let source = rest[..end].trim();
+                        match ExpressionParser::parse(source) {
+                            (Ok(expression), size) if size == source.len() => {
+                                response.push(Params::Named(name.to_string(), expression));
+                            }
+                            _ => return None
+                        }
+                        *slice = &rest[end..];
+                        if let Some(rest) = slice.strip_prefix(',') {
+                            *slice = rest;
+                        }
+                    }
+                }
+                Expect::Named(names) => {
+                    while let Some(capture) = VARIABLE_REGEX.captures(slice) {
+                        let name = capture.name("variable").unwrap().as_str();
+                        if new_line || !names.iter().any(|n| n == name) {
+                            break;
+                        }
+                        let rest = slice[name.len()..].trim_start();
+                        match ExpressionParser::parse(rest) {
+                            (Ok(expression), size) => {
+                                response.push(Params::Named(name.to_string(), expression));
+                                *slice = &rest[size..];
+                            }
+                            _ => return None
+                        }
+                        let untrimmed = *slice;
+                        *slice = slice.trim_start();
+                        new_line |= untrimmed[..untrimmed.len() - slice.len()].contains('\n');
+                    }
+                }
             }
         }
         return Some(response);
     }
+
+    // Length of an argument expression, up to the next top level comma or closing parenthesis.
+    fn argument_end(slice: &str) -> Option<usize> {
+        let mut depth = 0;
+        let mut chars = slice.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ',' if depth == 0 => return Some(index),
+                ')' | ']' | '}' => depth -= 1,
+                '"' => {
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '\\' => { chars.next(); }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '\n' => return None,
+                _ => {}
+            }
+        }
+        None
+    }
 }
@@ -0,0 +1,337 @@
+// Output Renderers
+use crate::story::Element;
+use crate::markup::Span;
+use crate::content::InputSpec;
+
+pub trait Renderer {
+    type Output;
+
+    fn render(&self, elements: &[Element]) -> Self::Output;
+
+    fn render_element(&self, index: usize, element: &Element) -> String {
+        match element {
+            Element::Text(text) => self.render_text(text),
+            Element::StyledText(spans) => self.render_spans(spans),
+            Element::Image { src, alt } => self.render_image(src, alt),
+            Element::Audio { src, looping, channel } => self.render_audio(src, *looping, channel.as_deref()),
+            Element::Delay { ms } => self.render_delay(*ms),
+            Element::Link(title, _)
+            | Element::ContentLink(title, _)
+            | Element::JumpLink(title, _, _, _)
+            | Element::Glossary { term: title, .. } => self.render_link(index, title),
+            Element::Input(variable, _, spec) => self.render_field(index, variable, spec),
+            Element::Form { title, fields, .. } => self.render_form(index, title, fields),
+            Element::Error(message) => self.render_error(message)
+        }
+    }
+
+    fn render_text(&self, text: &str) -> String {
+        text.to_string()
+    }
+
+    // Text with markup, rendered as plain text unless the renderer supports styles.
+    fn render_spans(&self, spans: &[Span]) -> String {
+        let text: String = spans.iter().map(|span| span.text.as_str()).collect();
+        self.render_text(&text)
+    }
+
+    // Images are shown as their alt text unless the renderer can display them.
+    fn render_image(&self, _src: &str, alt: &str) -> String {
+        self.render_text(alt)
+    }
+
+    // Audio is left to the host, renderers that can't play it produce nothing and skip the element.
+    fn render_audio(&self, _src: &str, _looping: bool, _channel: Option<&str>) -> String {
+        String::new()
+    }
+
+    // Pauses are also left to the host.
+    fn render_delay(&self, _ms: u32) -> String {
+        String::new()
+    }
+
+    // Called for Link, ContentLink, JumpLink and Glossary, index is the position to pass to send().
+    fn render_link(&self, _index: usize, title: &str) -> String {
+        format!("[{}]", title)
+    }
+
+    // An input followed by the error of the last value sent to it.
+    fn render_field(&self, index: usize, variable: &str, spec: &InputSpec) -> String {
+        match &spec.error {
+            Some(error) => format!("{} {}", self.render_input(index, variable, spec), self.render_error(error)),
+            None => self.render_input(index, variable, spec)
+        }
+    }
+
+    // The fields of a form followed by its submit link, all of them sent with the form's index.
+    fn render_form(&self, index: usize, title: &str, fields: &[(String, InputSpec)]) -> String {
+        let mut parts: Vec<String> = fields.iter().map(|(variable, spec)| self.render_field(index, variable, spec)).collect();
+        parts.push(self.render_link(index, title));
+        parts.join(" ")
+    }
+
+    // The prompt is shown before the field and the placeholder in it.
+    fn render_input(&self, _index: usize, _variable: &str, spec: &InputSpec) -> String {
+        let field = format!("[{}]", spec.placeholder.as_deref().unwrap_or("__________"));
+        match &spec.prompt {
+            Some(prompt) => format!("{} {}", prompt, field),
+            None => field
+        }
+    }
+
+    fn render_error(&self, message: &str) -> String {
+        format!("ERROR: {}", message)
+    }
+}
+
+// Renders one element per line, empty text elements become blank lines.
+pub struct PlainTextRenderer;
+
+impl Renderer for PlainTextRenderer {
+    type Output = String;
+
+    fn render(&self, elements: &[Element]) -> String {
+        elements.iter()
+            .enumerate()
+            .filter(|(_, element)| !matches!(element, Element::Audio { .. } | Element::Delay { .. }))
+            .map(|(index, element)| self.render_element(index, element))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub struct HtmlRenderer {
+    pub text_class: String,
+    pub link_class: String,
+    pub input_class: String,
+    pub image_class: String,
+    pub audio_class: String,
+    pub delay_class: String,
+    pub form_class: String,
+    pub error_class: String
+}
+
+impl Default for HtmlRenderer {
+    fn default() -> Self {
+        HtmlRenderer {
+            text_class: "lift-text".to_string(),
+            link_class: "lift-link".to_string(),
+            input_class: "lift-input".to_string(),
+            image_class: "lift-image".to_string(),
+            audio_class: "lift-audio".to_string(),
+            delay_class: "lift-delay".to_string(),
+            form_class: "lift-form".to_string(),
+            error_class: "lift-error".to_string()
+        }
+    }
+}
+
+impl HtmlRenderer {
+    pub fn escape(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#39;"),
+                _ => escaped.push(c)
+            }
+        }
+        escaped
+    }
+
+    fn spans_html(spans: &[Span]) -> String {
+        spans.iter().map(|span| {
+            let mut html = Self::escape(&span.text);
+            for (styled, tag) in [(span.code, "code"), (span.italic, "em"), (span.bold, "strong")] {
+                if styled {
+                    html = format!("<{}>{}</{}>", tag, html, tag);
+                }
+            }
+            html
+        }).collect()
+    }
+
+    fn paragraph(&self, html: &str) -> String {
+        format!("<p class=\"{}\">{}</p>", Self::escape(&self.text_class), html)
+    }
+}
+
+impl Renderer for HtmlRenderer {
+    type Output = String;
+
+    // Consecutive lines of text are one paragraph, with a <br> between lines. Paragraphs end at
+    // empty text and at any other element.
+    fn render(&self, elements: &[Element]) -> String {
+        let mut html = vec![];
+        let mut lines = vec![];
+        for (index, element) in elements.iter().enumerate() {
+            match element {
+                Element::Text(text) if !text.is_empty() => lines.push(Self::escape(text)),
+                Element::StyledText(spans) => lines.push(Self::spans_html(spans)),
+                _ => {
+                    if !lines.is_empty() {
+                        html.push(self.paragraph(&lines.join("<br>")));
+                        lines.clear();
+                    }
+                    html.push(self.render_element(index, element));
+                }
+            }
+        }
+        if !lines.is_empty() {
+            html.push(self.paragraph(&lines.join("<br>")));
+        }
+        html.retain(|html| !html.is_empty());
+        html.join("\n")
+    }
+
+    // Empty text elements only separate paragraphs, so they produce no markup.
+    fn render_text(&self, text: &str) -> String {
+        if text.is_empty() {
+            return String::new();
+        }
+        self.paragraph(&Self::escape(text))
+    }
+
+    fn render_spans(&self, spans: &[Span]) -> String {
+        self.paragraph(&Self::spans_html(spans))
+    }
+
+    fn render_image(&self, src: &str, alt: &str) -> String {
+        format!(
+            "<img class=\"{}\" src=\"{}\" alt=\"{}\">",
+            Self::escape(&self.image_class), Self::escape(src), Self::escape(alt)
+        )
+    }
+
+    fn render_audio(&self, src: &str, looping: bool, channel: Option<&str>) -> String {
+        let channel = channel.map_or(String::new(), |channel| format!(" data-channel=\"{}\"", Self::escape(channel)));
+        format!(
+            "<audio class=\"{}\" src=\"{}\"{}{} autoplay></audio>",
+            Self::escape(&self.audio_class), Self::escape(src), channel, if looping { " loop" } else { "" }
+        )
+    }
+
+    // An empty marker for scripts to pause at.
+    fn render_delay(&self, ms: u32) -> String {
+        format!("<span class=\"{}\" data-ms=\"{}\"></span>", Self::escape(&self.delay_class), ms)
+    }
+
+    fn render_link(&self, index: usize, title: &str) -> String {
+        format!(
+            "<a class=\"{}\" href=\"#\" data-index=\"{}\">{}</a>",
+            Self::escape(&self.link_class), index, Self::escape(title)
+        )
+    }
+
+    fn render_input(&self, index: usize, variable: &str, spec: &InputSpec) -> String {
+        let placeholder = spec.placeholder.as_ref().map_or(String::new(), |placeholder| {
+            format!(" placeholder=\"{}\"", Self::escape(placeholder))
+        });
+        let input = format!(
+            "<input class=\"{}\" type=\"text\" name=\"{}\" data-index=\"{}\"{}>",
+            Self::escape(&self.input_class), Self::escape(variable), index, placeholder
+        );
+        match &spec.prompt {
+            Some(prompt) => format!("<label>{} {}</label>", Self::escape(prompt), input),
+            None => input
+        }
+    }
+
+    fn render_form(&self, index: usize, title: &str, fields: &[(String, InputSpec)]) -> String {
+        let fields: Vec<String> = fields.iter().map(|(variable, spec)| self.render_field(index, variable, spec)).collect();
+        format!(
+            "<form class=\"{}\" data-index=\"{}\">{}<button type=\"submit\">{}</button></form>",
+            Self::escape(&self.form_class), index, fields.join(""), Self::escape(title)
+        )
+    }
+
+    fn render_error(&self, message: &str) -> String {
+        format!("<span class=\"{}\">{}</span>", Self::escape(&self.error_class), Self::escape(message))
+    }
+}
+
+// Numbers interactive elements, the returned vector maps choice N to output index choices[N - 1].
+pub struct TerminalRenderer {
+    pub no_color: bool
+}
+
+impl TerminalRenderer {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.no_color {
+            return text.to_string();
+        }
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+}
+
+enum Spacing {
+    Empty,
+    Text,
+    Break
+}
+
+impl Renderer for TerminalRenderer {
+    type Output = (String, Vec<usize>);
+
+    fn render(&self, elements: &[Element]) -> (String, Vec<usize>) {
+        let mut ret = String::new();
+        let mut choices = Vec::<usize>::new();
+        let mut last = Spacing::Empty;
+        for (index, element) in elements.iter().enumerate() {
+            let line = match element {
+                Element::Text(text) if text.is_empty() => {
+                    if let Spacing::Text = last {
+                        last = Spacing::Break;
+                    }
+                    continue;
+                }
+                Element::Audio { .. } | Element::Delay { .. } => continue,
+                _ if !element.is_interactive() => self.render_element(index, element),
+                _ => {
+                    choices.push(index);
+                    format!("{}. {}", choices.len(), self.render_element(index, element))
+                }
+            };
+            match last {
+                Spacing::Text => ret += "\n",
+                Spacing::Break => ret += "\n\n",
+                Spacing::Empty => {}
+            };
+            last = Spacing::Text;
+            ret += &line;
+        }
+        return (ret, choices);
+    }
+
+    fn render_spans(&self, spans: &[Span]) -> String {
+        spans.iter().map(|span| {
+            let codes: Vec<&str> = [(span.bold, "1"), (span.italic, "3"), (span.code, "33")].into_iter()
+                .filter(|(styled, _)| *styled)
+                .map(|(_, code)| code)
+                .collect();
+            match codes.is_empty() {
+                true => span.text.to_string(),
+                false => self.paint(&codes.join(";"), &span.text)
+            }
+        }).collect()
+    }
+
+    fn render_link(&self, _index: usize, title: &str) -> String {
+        self.paint("36", &format!("[{}]", title))
+    }
+
+    fn render_input(&self, _index: usize, _variable: &str, spec: &InputSpec) -> String {
+        let field = self.paint("36", &format!("[{}]", spec.placeholder.as_deref().unwrap_or("__________")));
+        match &spec.prompt {
+            Some(prompt) => format!("{} {}", prompt, field),
+            None => field
+        }
+    }
+
+    fn render_error(&self, message: &str) -> String {
+        self.paint("31", &format!("ERROR: {}", message))
+    }
+}
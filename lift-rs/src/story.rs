@@ -1,386 +1,2792 @@
 // Lift Interpreter Core
 use std::fmt;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::cell::{Cell, RefCell};
 use regex::Regex;
 use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
-use crate::content::{Page, Content, Action, PageAction};
+use crate::content::{Page, Content, Action, PageAction, TextContent, InputSpec, InputType};
+use crate::markup::Span;
 use crate::parser::ContentError;
-use crate::expression::StateManager;
-use crate::value::Value;
+use crate::expression::{Expression, StateManager, FunctionRegistry, HostFunction};
+use crate::value::{Value, ValueFormat, ordered};
+use crate::compact;
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "value")]
 pub enum Element {
+    // A line of text, an empty line separates paragraphs.
     Text(String),
+    // Text with markup, plain text is always Text.
+    StyledText(Vec<Span>),
+    // The source is passed through as written, for the host to resolve.
+    Image { src: String, alt: String },
+    // A sound for the host to play, channels let hosts layer sounds, such as music and effects.
+    Audio { src: String, #[serde(rename = "loop")] looping: bool, channel: Option<String> },
+    // A pause in milliseconds before the elements after it are shown. The interpreter doesn't wait,
+    // hosts that can't pause skip it.
+    Delay { ms: u32 },
     Link(String, String),
     ContentLink(String, PageAction),
-    JumpLink(String, String, PageAction),
-    Input(String, PageAction),
+    // The destination, the action and the destination taken if the action returns a false value.
+    JumpLink(String, String, PageAction, #[serde(default)] Option<String>),
+    // A term and the page defining it. Expanded is the number of elements after it showing the definition,
+    // None while the definition is hidden.
+    Glossary { term: String, page: String, expanded: Option<usize> },
+    Input(String, PageAction, #[serde(default)] InputSpec),
+    // Fields sent together with submit(), each one a variable and its constraints.
+    Form { title: String, action: PageAction, fields: Vec<(String, InputSpec)> },
     Error(String)
 }
 
+// Output elements in a stable shape for frontends, independent of how Element is stored.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputKind {
+    Text,
+    Link,
+    ContentLink,
+    JumpLink,
+    Glossary,
+    Input,
+    Form,
+    Image,
+    Audio,
+    Delay,
+    Error
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutputElement {
+    pub kind: OutputKind,
+    // The displayed text, or link title.
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    // Where a jump link goes when its action returns a false value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    // Position in output(), to pass to send(). Only set for interactive elements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variable: Option<String>,
+    // Why the last value sent to an input was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src: Option<String>,
+    #[serde(rename = "loop", skip_serializing_if = "Option::is_none")]
+    pub looping: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ms: Option<u32>,
+    // Whether the definition of a glossary term is shown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expanded: Option<bool>,
+    // The fields of a form, as inputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<OutputElement>>,
+    // Styled parts of the text, for text with markup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spans: Option<Vec<Span>>
+}
+
+impl From<&Element> for OutputElement {
+    fn from(element: &Element) -> Self {
+        let styled;
+        let (kind, text, destination, variable) = match element {
+            Element::Text(text) => (OutputKind::Text, text, None, None),
+            Element::StyledText(spans) => {
+                styled = spans.iter().map(|span| span.text.as_str()).collect::<String>();
+                (OutputKind::Text, &styled, None, None)
+            }
+            Element::Link(title, destination) => (OutputKind::Link, title, Some(destination), None),
+            Element::ContentLink(title, _) => (OutputKind::ContentLink, title, None, None),
+            Element::JumpLink(title, destination, _, _) => (OutputKind::JumpLink, title, Some(destination), None),
+            Element::Glossary { term, page, .. } => (OutputKind::Glossary, term, Some(page), None),
+            Element::Input(variable, _, _) => (OutputKind::Input, variable, None, Some(variable)),
+            Element::Form { title, .. } => (OutputKind::Form, title, None, None),
+            Element::Image { alt, .. } => (OutputKind::Image, alt, None, None),
+            Element::Audio { src, .. } => (OutputKind::Audio, src, None, None),
+            Element::Delay { .. } => {
+                styled = String::new();
+                (OutputKind::Delay, &styled, None, None)
+            }
+            Element::Error(message) => (OutputKind::Error, message, None, None)
+        };
+        OutputElement {
+            kind,
+            text: text.to_string(),
+            destination: destination.cloned(),
+            fallback: match element {
+                Element::JumpLink(_, _, _, fallback) => fallback.clone(),
+                _ => None
+            },
+            index: None,
+            variable: variable.cloned(),
+            error: match element {
+                Element::Input(_, _, spec) => spec.error.clone(),
+                _ => None
+            },
+            prompt: match element {
+                Element::Input(_, _, spec) => spec.prompt.clone(),
+                _ => None
+            },
+            placeholder: match element {
+                Element::Input(_, _, spec) => spec.placeholder.clone(),
+                _ => None
+            },
+            src: match element {
+                Element::Image { src, .. } | Element::Audio { src, .. } => Some(src.to_string()),
+                _ => None
+            },
+            looping: match element {
+                Element::Audio { looping, .. } => Some(*looping),
+                _ => None
+            },
+            channel: match element {
+                Element::Audio { channel, .. } => channel.clone(),
+                _ => None
+            },
+            ms: match element {
+                Element::Delay { ms } => Some(*ms),
+                _ => None
+            },
+            expanded: match element {
+                Element::Glossary { expanded, .. } => Some(expanded.is_some()),
+                _ => None
+            },
+            fields: match element {
+                Element::Form { action, fields, .. } => Some(fields.iter().map(|(variable, spec)| {
+                    OutputElement::from(&Element::Input(variable.to_string(), action.clone(), spec.clone()))
+                }).collect()),
+                _ => None
+            },
+            spans: match element {
+                Element::StyledText(spans) => Some(spans.clone()),
+                _ => None
+            }
+        }
+    }
+}
+
+impl Element {
+    // Links and inputs, which can be passed to send().
+    pub fn is_interactive(&self) -> bool {
+        !matches!(self, Element::Text(_) | Element::StyledText(_) | Element::Image { .. } | Element::Audio { .. } | Element::Delay { .. } | Element::Error(_))
+    }
+}
+
 enum StoryAction {
     Goto(String),
+    Break,
+    Continue,
+    Return(Value),
     Halt
 }
 
 struct StoryResult {
     output: Vec<Element>,
-    action: StoryAction
+    action: StoryAction,
+    // Set by @clear, the output before this result is removed when it is shown.
+    cleared: bool
 }
 
 impl StoryResult {
     fn new() -> Self {
-        StoryResult { output: Vec::<Element>::new(), action: StoryAction::Halt }
+        StoryResult { output: Vec::<Element>::new(), action: StoryAction::Halt, cleared: false }
+    }
+
+    // An action that is already pending, such as a goto, is never replaced by a later result.
+    fn combine(&mut self, mut result: StoryResult) {
+        if result.cleared {
+            self.output.clear();
+            self.cleared = true;
+        }
+        self.output.append(&mut result.output);
+        if let StoryAction::Halt = self.action {
+            self.action = result.action;
+        }
+    }
+
+    fn push(&mut self, element: Element) {
+        self.output.push(element);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Story {
+    first_page: String,
+    pages: HashMap<String, Page>,
+    #[serde(default)]
+    meta: StoryMeta,
+    // Page titles by their normalized form, only filled for stories with lenient titles.
+    #[serde(default)]
+    titles: HashMap<String, String>
+}
+
+// Declared in a frontmatter block at the top of the story:
+// ---
+// title: The Cave
+// start: Entrance
+// ---
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StoryMeta {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub version: Option<String>,
+    pub start: Option<String>,
+    // Keys lift doesn't know about, kept for tooling.
+    pub extra: HashMap<String, String>
+}
+
+#[derive(Debug)]
+pub enum StoryError {
+    Content { error: ContentError, page: String, line: usize, column: usize, source: String },
+    DuplicatePage(String, usize),
+    File(String, Box<StoryError>),
+    DuplicatePageInFiles(String, (String, usize), (String, usize)),
+    Frontmatter(String, usize),
+    MissingStart(String),
+    // Two titles that are the same when case and whitespace are ignored, with lenient titles.
+    AmbiguousPage(String, String, usize)
+}
+
+impl fmt::Display for StoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoryError::Content { error, page, line, column, source } => {
+                let margin = " ".repeat(line.to_string().len());
+                writeln!(f, "Parsing error on page '{}', line {}, column {}:", page, line, column)?;
+                write!(f, "{} |\n{} | {}\n{} | {}^\n", margin, line, source, margin, " ".repeat(column.saturating_sub(1)))?;
+                write!(f, "{}", error)
+            }
+            StoryError::DuplicatePage(page, line) =>
+                write!(f, "Duplicate page '{}' on line {}", page, line),
+            StoryError::File(file, error) =>
+                write!(f, "In file '{}': {}", file, error),
+            StoryError::DuplicatePageInFiles(page, (first_file, first_line), (file, line)) =>
+                write!(f, "Duplicate page '{}' in '{}' line {}, first defined in '{}' line {}",
+                    page, file, line, first_file, first_line),
+            StoryError::Frontmatter(error, line) =>
+                write!(f, "Frontmatter error on line {}: {}", line, error),
+            StoryError::MissingStart(page) =>
+                write!(f, "Start page '{}' does not exist", page),
+            StoryError::AmbiguousPage(page, other, line) =>
+                write!(f, "Page '{}' on line {} can't be told apart from page '{}'", page, line, other)
+        }
+    }
+}
+
+pub enum ValidationWarning {
+    MissingPage(String, String, String),
+    // The page, command, target page and the label missing from it.
+    MissingLabel(String, String, String, String),
+    DynamicTarget(String, String)
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationWarning::MissingPage(page, command, target) =>
+                write!(f, "Page '{}': @{} to missing page '{}'", page, command, target),
+            ValidationWarning::MissingLabel(page, command, target, label) =>
+                write!(f, "Page '{}': @{} to missing label '{}' on page '{}'", page, command, label, target),
+            ValidationWarning::DynamicTarget(page, command) =>
+                write!(f, "Page '{}': @{} has a dynamic target, unchecked", page, command)
+        }
+    }
+}
+
+// Steps recorded while tracing, each one names the page it happened on.
+#[derive(Clone)]
+pub enum TraceEvent {
+    PageEntered { page: String },
+    // command is "if", "elseif", "else", "switch" or "random", value is the evaluated condition,
+    // scrutinee or chosen option.
+    Branch { page: String, command: &'static str, value: Value, taken: bool },
+    LoopIteration { page: String, command: &'static str, iteration: usize },
+    VariableSet { page: String, variable: String, local: bool, value: Value },
+    Import { page: String, imported: String },
+    Goto { page: String, destination: String }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEvent::PageEntered { page } => write!(f, "{}: entered", page),
+            TraceEvent::Branch { page, command, value, taken } =>
+                write!(f, "{}: @{} {} {}", page, command, value, if *taken { "taken" } else { "skipped" }),
+            TraceEvent::LoopIteration { page, command, iteration } => write!(f, "{}: @{} iteration {}", page, command, iteration),
+            TraceEvent::VariableSet { page, variable, local, value } =>
+                write!(f, "{}: @{} {} = {}", page, if *local { "setlocal" } else { "set" }, variable, value),
+            TraceEvent::Import { page, imported } => write!(f, "{}: import '{}'", page, imported),
+            TraceEvent::Goto { page, destination } => write!(f, "{}: goto '{}'", page, destination)
+        }
+    }
+}
+
+// Suspicious behavior found while playing in strict mode, it doesn't change the story output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuntimeWarning {
+    UndefinedVariable { page: String, variable: String }
+}
+
+impl fmt::Display for RuntimeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeWarning::UndefinedVariable { page, variable } =>
+                write!(f, "Page '{}': read of undefined variable '{}'", page, variable)
+        }
+    }
+}
+
+// What the last play(), send() or submit() evaluated, see Interpreter::last_metrics().
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExecMetrics {
+    // Content nodes evaluated, such as lines, links and commands. Counted against the step limit.
+    pub nodes: usize,
+    pub iterations: usize,
+    pub pages: usize,
+    pub step_limit_reached: bool
+}
+
+// An @assert whose condition was false, collected in strict mode.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssertionFailure {
+    pub page: String,
+    pub message: String
+}
+
+impl fmt::Display for AssertionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Page '{}': {}", self.page, self.message)
+    }
+}
+
+// The variables and page of an interpreter at some point, compared with diff to see what a choice changed.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub current_page: String,
+    #[serde(serialize_with = "ordered::map")]
+    pub global: HashMap<String, Value>,
+    #[serde(serialize_with = "ordered::nested_map")]
+    pub local: HashMap<String, HashMap<String, Value>>
+}
+
+// A variable that differs between two snapshots, page is set for local variables.
+// Before is None for added variables and after is None for removed ones.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct VariableChange {
+    pub page: Option<String>,
+    pub variable: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StateDiff {
+    // The pages before and after, if the current page changed.
+    pub current_page: Option<(String, String)>,
+    pub added: Vec<VariableChange>,
+    pub removed: Vec<VariableChange>,
+    pub changed: Vec<VariableChange>
+}
+
+impl StateSnapshot {
+    // Changes from this snapshot to other, ordered by page and variable.
+    pub fn diff(&self, other: &StateSnapshot) -> StateDiff {
+        let mut diff = StateDiff::default();
+        if self.current_page != other.current_page {
+            diff.current_page = Some((self.current_page.to_string(), other.current_page.to_string()));
+        }
+        let empty = HashMap::new();
+        Self::diff_variables(None, &self.global, &other.global, &mut diff);
+        let mut pages: Vec<&String> = self.local.keys().chain(other.local.keys()).collect();
+        pages.sort();
+        pages.dedup();
+        for page in pages {
+            let before = self.local.get(page).unwrap_or(&empty);
+            let after = other.local.get(page).unwrap_or(&empty);
+            Self::diff_variables(Some(page), before, after, &mut diff);
+        }
+        diff
+    }
+
+    fn diff_variables(page: Option<&str>, before: &HashMap<String, Value>, after: &HashMap<String, Value>, diff: &mut StateDiff) {
+        let mut variables: Vec<&String> = before.keys().chain(after.keys()).collect();
+        variables.sort();
+        variables.dedup();
+        for variable in variables {
+            let change = VariableChange {
+                page: page.map(|page| page.to_string()),
+                variable: variable.to_string(),
+                before: before.get(variable).cloned(),
+                after: after.get(variable).cloned()
+            };
+            match (&change.before, &change.after) {
+                (None, Some(_)) => diff.added.push(change),
+                (Some(_), None) => diff.removed.push(change),
+                (Some(a), Some(b)) if a != b => diff.changed.push(change),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.current_page.is_none() && self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for VariableChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(page) = &self.page {
+            write!(f, "{}: ", page)?;
+        }
+        match (&self.before, &self.after) {
+            (None, Some(after)) => write!(f, "+ {} = {}", self.variable, after),
+            (Some(before), None) => write!(f, "- {} = {}", self.variable, before),
+            (Some(before), Some(after)) => write!(f, "~ {} = {} -> {}", self.variable, before, after),
+            (None, None) => write!(f, "{}", self.variable)
+        }
+    }
+}
+
+// One line per change, the page change first.
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = vec![];
+        if let Some((before, after)) = &self.current_page {
+            lines.push(format!("page: {} -> {}", before, after));
+        }
+        for change in self.added.iter().chain(&self.removed).chain(&self.changed) {
+            lines.push(change.to_string());
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    IndexOutOfRange(usize),
+    NotInteractive(usize),
+    NotAForm(usize),
+    MissingInput,
+    InvalidInput(String)
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::IndexOutOfRange(index) => write!(f, "No element at index {}", index),
+            SendError::NotInteractive(index) => write!(f, "Element {} is not a link or an input", index),
+            SendError::NotAForm(index) => write!(f, "Element {} is not a form", index),
+            SendError::MissingInput => write!(f, "Expected a value for the input"),
+            SendError::InvalidInput(error) => write!(f, "Invalid input: {}", error)
+        }
+    }
+}
+
+// Text, link titles and inputs a story can show, found by taking every branch without running the story.
+// Dynamic parts of the text are shown as $variable or ${...}.
+pub struct Coverage {
+    pub pages: Vec<String>,
+    pub texts: Vec<String>,
+    pub links: Vec<String>,
+    pub inputs: Vec<String>,
+    // Pages past the depth limit were not explored.
+    pub truncated: bool
+}
+
+// A transition out of a page, named by the command that makes it: link, goto, import or use.
+// Dynamic edges go to a page computed at runtime, shown as $variable or ${...}.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Edge {
+    Static { command: &'static str, destination: String },
+    Dynamic { command: &'static str, template: String }
+}
+
+// How a story source is split into pages. With a page_header_level of N only headers with exactly
+// N #s start pages and other headers are kept as text, 0 makes every header start a page.
+// With lenient_titles, pages are found ignoring case and extra whitespace, so "@goto the  cave"
+// goes to "The Cave".
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StoryConfig {
+    pub page_header_level: usize,
+    pub lenient_titles: bool
+}
+
+// Pages that can't be reached from the first page by following static links, gotos and imports.
+// Possibly reachable pages are only reachable through destinations computed at runtime.
+pub struct Reachability {
+    pub unreachable: Vec<String>,
+    pub possibly_reachable: Vec<String>
+}
+
+// The parsed pages with the line of their header, the errors, the first page and the frontmatter.
+type ParsedSource = (Vec<(String, usize, Page)>, Vec<StoryError>, Option<String>, StoryMeta);
+
+impl Story {
+    pub fn new(source: &str) -> Result<Self, StoryError> {
+        Self::with_config(source, StoryConfig::default())
+    }
+
+    pub fn with_config(source: &str, config: StoryConfig) -> Result<Self, StoryError> {
+        let (parsed, mut errors, first_page, meta) = Self::parse_source(source, config);
+        if !errors.is_empty() {
+            return Err(errors.remove(0));
+        }
+        let mut titles = HashMap::<String, String>::new();
+        if config.lenient_titles {
+            for (title, line, _) in parsed.iter() {
+                if let Some(other) = titles.insert(Self::normalize_title(title), title.to_string()) {
+                    return Err(StoryError::AmbiguousPage(title.to_string(), other, *line));
+                }
+            }
+        }
+        let pages = parsed.into_iter().map(|(title, _, page)| (title, page)).collect();
+        Ok(Story {pages, first_page: first_page.unwrap_or("".to_string()), meta, titles})
+    }
+
+    // Parses as much as possible, skipping pages with errors. The story is None if no page could be parsed.
+    pub fn new_lenient(source: &str) -> (Option<Self>, Vec<StoryError>) {
+        let (parsed, errors, first_page, meta) = Self::parse_source(source, StoryConfig::default());
+        if parsed.is_empty() && !errors.is_empty() {
+            return (None, errors);
+        }
+        let pages = parsed.into_iter().map(|(title, _, page)| (title, page)).collect();
+        (Some(Story {pages, first_page: first_page.unwrap_or("".to_string()), meta, titles: HashMap::new()}), errors)
+    }
+
+    // Like new, with a warning for every static link, goto and import to a page that doesn't exist.
+    pub fn new_with_warnings(source: &str) -> Result<(Self, Vec<ValidationWarning>), StoryError> {
+        let story = Self::new(source)?;
+        let warnings = story.validate().into_iter()
+            .filter(|warning| matches!(warning, ValidationWarning::MissingPage(..)))
+            .collect();
+        Ok((story, warnings))
+    }
+
+    // The parsed story, to be loaded with from_compiled without parsing the source again.
+    pub fn compile(&self) -> Result<Vec<u8>, CompileError> {
+        let compiled = CompiledStory { version: COMPILE_VERSION, story: self };
+        serde_json::to_vec(&compiled).map_err(CompileError::Json)
+    }
+
+    pub fn from_compiled(data: &[u8]) -> Result<Self, CompileError> {
+        // The version is checked first, so stale artifacts aren't reported as invalid.
+        let header = serde_json::from_slice::<CompiledStory<serde::de::IgnoredAny>>(data).map_err(CompileError::Json)?;
+        if header.version != COMPILE_VERSION {
+            return Err(CompileError::Version(header.version));
+        }
+        let compiled = serde_json::from_slice::<CompiledStory<Story>>(data).map_err(CompileError::Json)?;
+        Ok(compiled.story)
+    }
+
+    // Builds a story from (file name, source) pairs, the first page is the first header of the first file.
+    // Only the frontmatter of the first file is used.
+    pub fn from_sources(sources: &[(&str, &str)]) -> Result<Self, StoryError> {
+        let mut pages = HashMap::<String, Page>::new();
+        let mut origins = HashMap::<String, (String, usize)>::new();
+        let mut first_page: Option<String> = None;
+        let mut meta: Option<StoryMeta> = None;
+        for (file, source) in sources {
+            let (parsed, mut errors, _, file_meta) = Self::parse_source(source, StoryConfig::default());
+            if meta.is_none() {
+                meta = Some(file_meta);
+            }
+            // The start page can be in any file, it's checked once all of them are parsed.
+            errors.retain(|error| !matches!(error, StoryError::MissingStart(_)));
+            if !errors.is_empty() {
+                return Err(StoryError::File(file.to_string(), Box::new(errors.remove(0))));
+            }
+            for (title, line, page) in parsed {
+                if let Some(origin) = origins.get(&title) {
+                    return Err(StoryError::DuplicatePageInFiles(title, origin.clone(), (file.to_string(), line)));
+                }
+                if first_page.is_none() {
+                    first_page = Some(Self::base_title(&title).to_string());
+                }
+                origins.insert(title.to_string(), (file.to_string(), line));
+                pages.insert(title, page);
+            }
+        }
+        let meta = meta.unwrap_or_default();
+        if let Some(start) = &meta.start {
+            if !pages.contains_key(start) {
+                return Err(StoryError::File(sources[0].0.to_string(), Box::new(StoryError::MissingStart(start.to_string()))));
+            }
+            first_page = Some(start.to_string());
+        }
+        Ok(Story {pages, first_page: first_page.unwrap_or("".to_string()), meta, titles: HashMap::new()})
+    }
+
+    // Parses the frontmatter block, if the source starts with one.
+    fn parse_meta(source: &str) -> Result<StoryMeta, StoryError> {
+        let mut meta = StoryMeta::default();
+        let mut lines = source.lines().enumerate();
+        match lines.next() {
+            Some((_, line)) if line.trim_end() == "---" => {}
+            _ => return Ok(meta)
+        }
+        let mut keys = HashSet::<String>::new();
+        for (line_number, line) in lines {
+            let line_number = line_number + 1;
+            let line = line.trim();
+            if line == "---" {
+                return Ok(meta);
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(StoryError::Frontmatter(format!("Expected 'key: value', found '{}'", line), line_number))
+            };
+            if key.is_empty() {
+                return Err(StoryError::Frontmatter("Missing key".to_string(), line_number));
+            }
+            if !keys.insert(key.to_string()) {
+                return Err(StoryError::Frontmatter(format!("Duplicate key '{}'", key), line_number));
+            }
+            let value = match value.len() >= 2 && (value.starts_with('"') && value.ends_with('"') || value.starts_with('\'') && value.ends_with('\'')) {
+                true => &value[1..value.len() - 1],
+                false => value
+            }.to_string();
+            match key {
+                "title" => meta.title = Some(value),
+                "author" => meta.author = Some(value),
+                "version" => meta.version = Some(value),
+                "start" => meta.start = Some(value),
+                _ => {
+                    meta.extra.insert(key.to_string(), value);
+                }
+            }
+        }
+        Err(StoryError::Frontmatter("Unterminated frontmatter, expected '---'".to_string(), 1))
+    }
+
+    // Returns every page that could be parsed in source order along with the line of its header,
+    // the errors found on the remaining pages, the title of the first page and the frontmatter.
+    fn parse_source(source: &str, config: StoryConfig) -> ParsedSource {
+        lazy_static! {
+            static ref HEADER_REGEX: Regex = Regex::new(r"^(?P<level>#+)(?P<title>.+)").unwrap();
+        }
+
+        let mut pages = Vec::<(String, usize, Page)>::new();
+        let mut errors = Vec::<StoryError>::new();
+        let meta = Self::parse_meta(source).unwrap_or_else(|error| {
+            errors.push(error);
+            StoryMeta::default()
+        });
+        let mut titles = Vec::<String>::new();
+        let mut content_acumulator = "".to_string();
+        let mut content_lines = Vec::<usize>::new();
+        let mut current_page: Option<&str> = None;
+        let mut current_tags = Vec::<String>::new();
+
+        let mut page_line: usize = 1;
+        for (line_number, source_line) in source.lines().enumerate() {
+            let (line, has_comment) = Self::strip_comment(source_line);
+            let header = HEADER_REGEX.captures(line).filter(|capture| {
+                config.page_header_level == 0 || capture["level"].len() == config.page_header_level
+            });
+            if let Some(capture) = header {
+                if let Some(title) = current_page {
+                    match Self::parse_page(page_line, &content_lines, title, &current_tags, &content_acumulator) {
+                        Ok(page) => pages.push((title.to_string(), page_line, page)),
+                        Err(error) => errors.push(error)
+                    }
+                    content_acumulator = "".to_string();
+                    content_lines.clear();
+                }
+                page_line = line_number + 1;
+                let (title, tags) = Self::split_tags(capture.name("title").unwrap().as_str());
+                current_tags = tags;
+                if titles.iter().any(|t| t == title) {
+                    // Skip the duplicate page, the first definition is kept.
+                    errors.push(StoryError::DuplicatePage(title.to_string(), page_line));
+                    current_page = None;
+                    continue;
+                }
+                titles.push(title.to_string());
+                current_page = Some(title);
+            } else if let Some(_) = current_page {
+                // Lines holding only a comment are dropped so they don't produce empty text.
+                if has_comment && line.trim().is_empty() {
+                    continue;
+                }
+                content_acumulator += &format!("{}\n", line);
+                content_lines.push(line_number + 1);
+            }
+        }
+        if let Some(title) = current_page {
+            match Self::parse_page(page_line, &content_lines, title, &current_tags, &content_acumulator) {
+                Ok(page) => pages.push((title.to_string(), page_line, page)),
+                Err(error) => errors.push(error)
+            }
+        }
+        let mut first_page = titles.first().map(|title| Self::base_title(title).to_string());
+        if let Some(start) = &meta.start {
+            match titles.contains(start) {
+                true => first_page = Some(start.to_string()),
+                false => errors.push(StoryError::MissingStart(start.to_string()))
+            }
+        }
+        (pages, errors, first_page, meta)
+    }
+
+    // Reports every static link, goto and import that references a missing page.
+    pub fn validate(&self) -> Vec<ValidationWarning> {
+        let mut titles: Vec<&String> = self.pages.keys().collect();
+        titles.sort();
+        let mut warnings = vec![];
+        for title in titles {
+            for (command, target) in self.pages[title].targets() {
+                let target = target.as_static().map(|page| self.resolve(title, &page));
+                match target.as_deref().map(|target| self.split_label(target)) {
+                    Some((page, _)) if !self.pages.contains_key(page) => {
+                        warnings.push(ValidationWarning::MissingPage(title.to_string(), command.to_string(), page.to_string()));
+                    }
+                    Some((page, Some(label))) if self.pages[page].label(label).is_none() => {
+                        warnings.push(ValidationWarning::MissingLabel(title.to_string(), command.to_string(), page.to_string(), label.to_string()));
+                    }
+                    None => warnings.push(ValidationWarning::DynamicTarget(title.to_string(), command.to_string())),
+                    _ => {}
+                }
+            }
+        }
+        warnings
+    }
+
+    pub fn first_page(&self) -> &str {
+        &self.first_page
+    }
+
+    pub fn meta(&self) -> &StoryMeta {
+        &self.meta
+    }
+
+    // Pages in no particular order.
+    pub fn pages(&self) -> impl Iterator<Item = (&str, &Page)> {
+        self.pages.iter().map(|(title, page)| (title.as_str(), page))
+    }
+
+    // Sorted titles of the pages with a tag, a page counts once if any of its translations has it.
+    pub fn pages_with_tag(&self, tag: &str) -> Vec<&str> {
+        let mut titles: Vec<&str> = self.pages.iter()
+            .filter(|(_, page)| page.tags.iter().any(|t| t == tag))
+            .map(|(title, _)| Self::base_title(title))
+            .collect();
+        titles.sort();
+        titles.dedup();
+        titles
+    }
+
+    // Outgoing transitions of a page, in the order they appear. Empty if the page doesn't exist.
+    pub fn edges(&self, title: &str) -> Vec<Edge> {
+        let page = match self.pages.get(title) {
+            Some(page) => page,
+            None => return vec![]
+        };
+        page.targets().into_iter().map(|(command, target)| match target.as_static() {
+            Some(destination) => {
+                let destination = self.resolve(title, &destination);
+                Edge::Static { command, destination: self.split_label(&destination).0.to_string() }
+            }
+            None => Edge::Dynamic { command, template: target.template() }
+        }).collect()
+    }
+
+    pub fn reachability(&self) -> Reachability {
+        let mut reachable = HashSet::<&str>::new();
+        let mut dynamic = Vec::<&TextContent>::new();
+        self.visit(vec![self.first_page.as_str()], &mut reachable, &mut dynamic);
+
+        let mut possible = HashSet::<&str>::new();
+        let mut dynamic_count = 0;
+        while dynamic_count < dynamic.len() {
+            dynamic_count = dynamic.len();
+            let matches: Vec<&str> = self.pages.keys()
+                .map(|title| title.as_str())
+                .filter(|title| !reachable.contains(title) && !possible.contains(title))
+                .filter(|title| dynamic.iter().any(|target| target.could_match(title)))
+                .collect();
+            self.visit(matches, &mut possible, &mut dynamic);
+        }
+
+        let mut unreachable = vec![];
+        let mut possibly_reachable = vec![];
+        for title in self.pages.keys() {
+            if possible.contains(title.as_str()) && !reachable.contains(title.as_str()) {
+                possibly_reachable.push(title.to_string());
+            }
+            else if !reachable.contains(title.as_str()) {
+                unreachable.push(title.to_string());
+            }
+        }
+        unreachable.sort();
+        possibly_reachable.sort();
+        Reachability { unreachable, possibly_reachable }
+    }
+
+    // Walks pages reachable through static targets, up to max_depth links away from the first page.
+    pub fn coverage(&self, max_depth: usize) -> Coverage {
+        let mut coverage = Coverage { pages: vec![], texts: vec![], links: vec![], inputs: vec![], truncated: false };
+        let mut visited = HashSet::<&str>::new();
+        let mut queue = std::collections::VecDeque::from([(self.first_page.as_str(), 0)]);
+        while let Some((title, depth)) = queue.pop_front() {
+            let page = match self.pages.get_key_value(title) {
+                Some((key, page)) if visited.insert(key.as_str()) => page,
+                _ => continue
+            };
+            if depth > max_depth {
+                coverage.truncated = true;
+                continue;
+            }
+            coverage.pages.push(title.to_string());
+            let mut outputs = (vec![], vec![], vec![]);
+            Content::collect_outputs(&page.content, &mut outputs);
+            for action in page.actions.iter() {
+                Content::collect_outputs(action, &mut outputs);
+            }
+            for (list, found) in [
+                (&mut coverage.texts, outputs.0),
+                (&mut coverage.links, outputs.1),
+                (&mut coverage.inputs, outputs.2)
+            ] {
+                for item in found {
+                    if !list.contains(&item) {
+                        list.push(item);
+                    }
+                }
+            }
+            for (_, target) in page.targets() {
+                let destination = target.as_static().map(|destination| self.resolve(title, &destination));
+                if let Some((key, _)) = destination.and_then(|destination| self.pages.get_key_value(self.split_label(&destination).0)) {
+                    queue.push_back((key.as_str(), depth + 1));
+                }
+            }
+        }
+        coverage
+    }
+
+    // Marks every page statically reachable from the given pages, collecting dynamic targets along the way.
+    fn visit<'a>(&'a self, mut queue: Vec<&'a str>, visited: &mut HashSet<&'a str>, dynamic: &mut Vec<&'a TextContent>) {
+        while let Some(title) = queue.pop() {
+            if !visited.insert(title) {
+                continue;
+            }
+            // Translations are reached along with their page.
+            for variant in self.pages.keys().filter(|key| key.as_str() != title && Self::base_title(key) == title) {
+                queue.push(variant.as_str());
+            }
+            if let Some(page) = self.pages.get(title) {
+                for (_, target) in page.targets() {
+                    match target.as_static().map(|destination| self.resolve(title, &destination)) {
+                        Some(destination) => {
+                            if let Some((key, _)) = self.pages.get_key_value(self.split_label(&destination).0) {
+                                queue.push(key.as_str());
+                            }
+                        }
+                        None => dynamic.push(target)
+                    }
+                }
+            }
+        }
+    }
+
+    // Removes an @@ comment from a line, a comment can be escaped as \@@
+    // String literals in expressions keep their @@, on command lines and inside ${...}.
+    // A line starting with // is a comment as a whole, \// starts a line with a literal //.
+    fn strip_comment(line: &str) -> (&str, bool) {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("//") {
+            return ("", true);
+        }
+        let command = trimmed.starts_with('@') && !trimmed.starts_with("@@");
+        // Open braces of ${...} interpolations.
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut chars = line.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                // A quote without a closing one is just a quote.
+                '"' if (command || depth > 0) && (in_string || line[index + 1..].contains('"')) => in_string = !in_string,
+                _ if in_string => {}
+                '$' if line[index..].starts_with("${") => {
+                    depth += 1;
+                    chars.next();
+                }
+                '{' if depth > 0 => depth += 1,
+                '}' if depth > 0 => depth -= 1,
+                _ if line[index..].starts_with("@@") => return (line[..index].trim_end(), true),
+                _ => {}
+            }
+        }
+        (line, false)
+    }
+
+    // Splits the tags off a header, "Market #shop #town" is the page "Market" tagged shop and town.
+    // Tags start with a letter, so a title like "Room #2" is kept whole.
+    fn split_tags(header: &str) -> (&str, Vec<String>) {
+        lazy_static! {
+            static ref TAG_REGEX: Regex = Regex::new(r"^#[A-Za-z_][A-Za-z0-9_-]*$").unwrap();
+        }
+        let mut title = header.trim();
+        let mut tags = vec![];
+        while let Some((rest, word)) = title.rsplit_once(char::is_whitespace) {
+            if rest.trim().is_empty() || !TAG_REGEX.is_match(word) {
+                break;
+            }
+            tags.push(word[1..].to_string());
+            title = rest.trim_end();
+        }
+        tags.reverse();
+        (title, tags)
+    }
+
+    // content_lines holds the source line number of every line in content.
+    fn parse_page(line_number: usize, content_lines: &[usize], title: &str, tags: &[String], content: &str) -> Result<Page, StoryError> {
+        let mut page = Page::parse(title, content).map_err(|(size, error)| {
+            // Errors at the end of the page point past the last line break, keep them on the last line.
+            let size = if size > 0 && size == content.len() && content.ends_with('\n') { size - 1 } else { size };
+            let line_index = content[..size].matches('\n').count();
+            let line = *content_lines.get(line_index).or(content_lines.last()).unwrap_or(&line_number);
+            let line_start = content[..size].rfind('\n').map_or(0, |i| i + 1);
+            let column = content[line_start..size].chars().count() + 1;
+            let source = content[line_start..].lines().next().unwrap_or("").to_string();
+            StoryError::Content { error, page: title.to_string(), line, column, source }
+        })?;
+        page.tags = tags.to_vec();
+        Ok(page)
+    }
+
+    // FNV-1a hash of the sorted page titles, stable between runs and platforms.
+    fn fingerprint(&self) -> u64 {
+        let mut titles: Vec<&String> = self.pages.keys().collect();
+        titles.sort();
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for title in titles {
+            for byte in title.bytes().chain(std::iter::once(0)) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    // The page a title is nested in, "Town.Market" is a child of "Town" if there is a page "Town".
+    // Dots with spaces next to them, as in "Mr. Smith", don't separate names.
+    pub fn parent(&self, title: &str) -> Option<&str> {
+        let (parent, name) = Self::base_title(title).rsplit_once('.')?;
+        if parent.is_empty() || parent.ends_with(char::is_whitespace) || name.is_empty() || name.starts_with(char::is_whitespace) {
+            return None;
+        }
+        self.pages.get_key_value(parent).map(|(key, _)| key.as_str())
+    }
+
+    // Sorted titles of the pages nested directly in a page.
+    pub fn children(&self, title: &str) -> Vec<&str> {
+        let mut children: Vec<&str> = self.pages.keys()
+            .map(|key| Self::base_title(key))
+            .filter(|key| self.parent(key) == Some(title))
+            .collect();
+        children.sort();
+        children.dedup();
+        children
+    }
+
+    // The pages a page is nested in, outermost first.
+    pub fn ancestors(&self, title: &str) -> Vec<&str> {
+        let mut ancestors = vec![];
+        let mut current = self.parent(title);
+        while let Some(parent) = current {
+            ancestors.insert(0, parent);
+            current = self.parent(parent);
+        }
+        ancestors
+    }
+
+    // Destinations are looked up from the page they appear on: as a child of it, then as a child of
+    // each page it is nested in, nearest first, and last as written. From "Town.Square", "Market" is
+    // "Town.Square.Market", then "Town.Market", then "Market". Qualified names like "Town.Market" only
+    // match as written unless a nested page has the same name.
+    pub fn resolve(&self, from: &str, name: &str) -> String {
+        let mut scope = Some(Self::base_title(from));
+        while let Some(prefix) = scope {
+            let candidate = format!("{}.{}", prefix, name);
+            if let Some(title) = self.title(&candidate) {
+                return title.to_string();
+            }
+            scope = self.parent(prefix);
+        }
+        // "Fight#start" is the label start on the page Fight.
+        if let (None, Some((page, label))) = (self.title(name), Self::label_target(name)) {
+            return format!("{}#{}", self.resolve(from, page), label);
+        }
+        self.title(name).unwrap_or(name).to_string()
+    }
+
+    fn label_target(target: &str) -> Option<(&str, &str)> {
+        lazy_static! {
+            static ref LABEL_REGEX: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+        }
+        target.rsplit_once('#').filter(|(page, label)| !page.trim().is_empty() && LABEL_REGEX.is_match(label))
+    }
+
+    // Splits a resolved target into its page and label. A target naming a page is never split.
+    pub fn split_label<'a>(&self, target: &'a str) -> (&'a str, Option<&'a str>) {
+        match (self.title(target), Self::label_target(target)) {
+            (None, Some((page, label))) if self.title(page).is_some() => (page, Some(label)),
+            _ => (target, None)
+        }
+    }
+
+    // The title a page is stored under, ignoring case and extra whitespace for stories with lenient titles.
+    fn title(&self, title: &str) -> Option<&str> {
+        match self.pages.get_key_value(title) {
+            Some((key, _)) => Some(key),
+            None => self.titles.get(&Self::normalize_title(title)).map(|key| key.as_str())
+        }
+    }
+
+    fn normalize_title(title: &str) -> String {
+        title.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+    }
+
+    // A page title without its language suffix: "Intro@es" is the Spanish variant of "Intro".
+    fn base_title(title: &str) -> &str {
+        lazy_static! {
+            static ref LANGUAGE_REGEX: Regex = Regex::new(r"^[A-Za-z]{2,3}(-[A-Za-z0-9]+)*$").unwrap();
+        }
+        match title.rsplit_once('@') {
+            Some((base, language)) if !base.is_empty() && LANGUAGE_REGEX.is_match(language) => base.trim_end(),
+            _ => title
+        }
+    }
+
+    // The page in the given language, or the page without a language if there is no translation.
+    fn page(&self, title: &str, language: Option<&str>) -> Option<&Page> {
+        language
+            .and_then(|language| self.title(&format!("{}@{}", title, language)))
+            .or_else(|| self.title(title))
+            .and_then(|title| self.pages.get(title))
+    }
+
+    // Actions always name the page they were parsed in, so the title only needs resolving for stories loaded without it.
+    fn get_action(&self, action: &PageAction) -> Option<Arc<Vec<Content>>> {
+        if let Some(content) = &action.content {
+            return Some(Arc::clone(content));
+        }
+        let page = match self.pages.get(&action.page) {
+            Some(page) => page,
+            None => self.pages.get(self.title(&action.page)?)?
+        };
+        return page.actions.get(action.index).cloned();
+    }
+
+    // The action with its content, elements are made with it so sending them doesn't look it up.
+    fn resolved(&self, action: &PageAction) -> PageAction {
+        PageAction { content: self.get_action(action), ..action.clone() }
+    }
+}
+
+// Compiled stories from other versions are rejected, since the parsed format changes between them.
+// Bump it whenever Content or the other parsed types change. Version 2 added the commands written
+// after compiling was, such as @wait, @assert, @setup, @let, @clear, @label and forms.
+const COMPILE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct CompiledStory<S> {
+    version: u32,
+    story: S
+}
+
+pub enum CompileError {
+    Json(serde_json::Error),
+    Version(u32)
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Json(error) => write!(f, "Invalid compiled story: {}", error),
+            CompileError::Version(version) =>
+                write!(f, "Compiled story version {} is not supported, expected version {}", version, COMPILE_VERSION)
+        }
+    }
+}
+
+const SAVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct SavedGame {
+    version: u32,
+    #[serde(default)]
+    fingerprint: Option<u64>,
+    state: State
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Json(serde_json::Error),
+    // The save was made by a newer version of lift.
+    Version(u32),
+    // The save belongs to a story with different pages.
+    DifferentStory,
+    // The page the save was on doesn't exist in the story.
+    MissingPage(String),
+    // A compact save that couldn't be decoded.
+    Compact(String)
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Json(error) => write!(f, "Invalid save data: {}", error),
+            LoadError::Version(version) =>
+                write!(f, "Save version {} is not supported, the latest supported version is {}", version, SAVE_VERSION),
+            LoadError::DifferentStory => write!(f, "The save belongs to a different story"),
+            LoadError::MissingPage(page) => write!(f, "The saved page '{}' does not exist in the story", page),
+            LoadError::Compact(error) => write!(f, "Invalid compact save data: {}", error)
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct State {
+    current_page: String,
+    #[serde(serialize_with = "ordered::map")]
+    global: HashMap<String, Value>,
+    #[serde(serialize_with = "ordered::nested_map")]
+    local: HashMap<String, HashMap<String, Value>>,
+    output: Vec<Element>,
+    #[serde(default, serialize_with = "ordered::map")]
+    visits: HashMap<String, u32>,
+    // The pages that led to the current one, like breadcrumbs.
+    #[serde(default)]
+    path: Vec<String>,
+    #[serde(default, serialize_with = "ordered::set")]
+    consumed: HashSet<(String, usize)>,
+    // Globals declared with @const.
+    #[serde(default, serialize_with = "ordered::set")]
+    constants: HashSet<String>,
+    // Variables declared with @let, a frame for each block being evaluated along with the page it runs in.
+    // Frames only exist while content is evaluated, so they are never saved.
+    #[serde(skip)]
+    blocks: Vec<(String, HashMap<String, Value>)>,
+    // Random number generator state, saved so a loaded game continues the same random sequence.
+    #[serde(default = "State::random_seed")]
+    rng: Cell<u64>
+}
+
+impl State {
+    fn new(first_page: &str) -> State {
+        State {
+            current_page: first_page.to_string(),
+            global: HashMap::new(),
+            local: HashMap::new(),
+            output: vec![],
+            visits: HashMap::new(),
+            path: vec![],
+            consumed: HashSet::new(),
+            constants: HashSet::new(),
+            blocks: vec![],
+            rng: Self::random_seed()
+        }
+    }
+
+    // Going back to a page already on the path drops the pages visited after it, so loops don't
+    // make the path grow and it never holds a page twice.
+    fn enter(&mut self, page: &str) {
+        match self.path.iter().position(|visited| visited == page) {
+            Some(index) => self.path.truncate(index + 1),
+            None => self.path.push(page.to_string())
+        }
+    }
+
+    fn random_seed() -> Cell<u64> {
+        Cell::new(fastrand::u64(..))
+    }
+
+    fn with_rng<T>(&self, f: impl FnOnce(&fastrand::Rng) -> T) -> T {
+        let rng = fastrand::Rng::with_seed(self.rng.get());
+        let value = f(&rng);
+        self.rng.set(rng.get_seed());
+        value
+    }
+
+    fn is_consumed(&self, action: &PageAction) -> bool {
+        action.once && self.consumed.contains(&(action.page.to_string(), action.index))
+    }
+
+    fn consume(&mut self, action: &PageAction) {
+        if action.once {
+            self.consumed.insert((action.page.to_string(), action.index));
+        }
+    }
+
+    fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            current_page: self.current_page.to_string(),
+            global: self.global.clone(),
+            local: self.local.clone()
+        }
+    }
+
+    // Functions that depend on the state, resolved before host and built-in functions.
+    fn eval_function(&self, name: &str, arguments: &[Value]) -> Option<Result<Value, String>> {
+        let value = match (name, arguments.first()) {
+            ("visited", Some(Value::Text(page))) => Value::Boolean(self.visits.contains_key(page)),
+            ("visit_count", Some(Value::Text(page))) => {
+                Value::Integer(*self.visits.get(page).unwrap_or(&0) as i64)
+            }
+            ("visited", _) | ("visit_count", _) => Value::Null,
+            // Read a variable past the one shadowing it, a page local or @let hides a global of the same name.
+            ("global", Some(Value::Text(variable))) => self.global.get(variable).cloned().unwrap_or(Value::Null),
+            ("local", Some(Value::Text(variable))) => self.get_local(&self.current_page, variable).cloned().unwrap_or(Value::Null),
+            ("global", _) | ("local", _) => Value::Null,
+            ("pickrandom", _) | ("pick", _) | ("pick_weighted", _) | ("probability", _) | ("rand", _) => {
+                return self.with_rng(|rng| Value::eval_random(name, arguments, rng));
+            }
+            ("roll", _) | ("roll_all", _) => return self.with_rng(|rng| Value::eval_dice(name, arguments, rng)),
+            _ => return None
+        };
+        Some(Ok(value))
+    }
+
+    fn get(&self, page: &str, variable: &str) -> Option<&Value> {
+        self.get_local(page, variable).or_else(|| self.global.get(variable))
+    }
+
+    // A @let or page local, ignoring globals.
+    fn get_local(&self, page: &str, variable: &str) -> Option<&Value> {
+        let block = self.blocks.iter().rev()
+            .filter(|(block_page, _)| block_page == page)
+            .find_map(|(_, frame)| frame.get(variable));
+        if block.is_some() {
+            return block;
+        }
+        self.local.get(page).and_then(|state| state.get(variable))
+    }
+
+    fn set(&mut self, variable: &str, value: Value) {
+        self.global.insert(variable.to_string(), value);
+    }
+
+    fn set_index(&mut self, variable: &str, indices: &Vec<Value>, value: Value) -> Option<()> {
+        if self.constants.contains(variable) {
+            return None;
+        }
+        if let Some(frame) = self.block(variable) {
+            return Self::assign(frame, variable, indices, value);
+        }
+        if indices.is_empty() {
+            self.set(variable, value);
+            return Some(());
+        }
+        Self::assign(&mut self.global, variable, indices, value)
+    }
+
+    // The innermost block of the current page declaring the variable, assignments go there
+    // instead of to the page or global variable it hides.
+    fn block(&mut self, variable: &str) -> Option<&mut HashMap<String, Value>> {
+        let page = &self.current_page;
+        self.blocks.iter_mut().rev()
+            .find(|(block_page, frame)| block_page == page && frame.contains_key(variable))
+            .map(|(_, frame)| frame)
+    }
+
+    fn set_block(&mut self, variable: &str, value: Value) -> Option<()> {
+        if self.constants.contains(variable) {
+            return None;
+        }
+        let (_, frame) = self.blocks.last_mut()?;
+        frame.insert(variable.to_string(), value);
+        Some(())
+    }
+
+    // Declaring a constant again with the same value does nothing, so pages declaring constants can be revisited.
+    fn set_constant(&mut self, variable: &str, value: Value) -> Result<(), String> {
+        match self.global.get(variable) {
+            Some(current) if self.constants.contains(variable) => match *current == value {
+                true => Ok(()),
+                false => Err(format!("Constant {} is already defined as {}", variable, current))
+            },
+            Some(_) => Err(format!("Cannot declare {} as a constant, it is already set", variable)),
+            None => {
+                self.set(variable, value);
+                self.constants.insert(variable.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // A missing variable is created as a list or object, depending on its first index,
+    // and removed again if the assignment fails.
+    fn assign(variables: &mut HashMap<String, Value>, variable: &str, indices: &Vec<Value>, value: Value) -> Option<()> {
+        let created = !variables.contains_key(variable);
+        let var = variables.entry(variable.to_string()).or_insert(Value::Null);
+        match var.get_mut(indices) {
+            Some(reference) => {
+                *reference = value;
+                Some(())
+            }
+            None => {
+                if created {
+                    variables.remove(variable);
+                }
+                None
+            }
+        }
+    }
+
+    // A local with the name of a constant would hide it, so None is returned and nothing is set.
+    fn set_local(&mut self, variable: &str, value: Value) -> Option<()> {
+        if self.constants.contains(variable) {
+            return None;
+        }
+        let state = match self.local.get_mut(&self.current_page) {
+            Some(state) => state,
+            None => {
+                self.local.insert(self.current_page.to_string(), HashMap::new());
+                self.local.get_mut(&self.current_page)?
+            }
+        };
+        state.insert(variable.to_string(), value);
+        Some(())
+    }
+
+    fn set_local_index(&mut self, variable: &str, indices: &Vec<Value>, value: Value) -> Option<()> {
+        if self.constants.contains(variable) {
+            return None;
+        }
+        if let Some(frame) = self.block(variable) {
+            return Self::assign(frame, variable, indices, value);
+        }
+        if indices.is_empty() {
+            return self.set_local(variable, value);
+        }
+        let state = self.local.entry(self.current_page.to_string()).or_default();
+        Self::assign(state, variable, indices, value)
+    }
+}
+
+impl StateManager for State {
+    fn get(&self, variable: &str) -> Option<&Value> {
+        self.get(&self.current_page, variable)
+    }
+}
+
+// State as seen by expressions, with access to host functions.
+struct Scope<'a> {
+    story: &'a Story,
+    language: Option<&'a str>,
+    state: &'a State,
+    functions: &'a RefCell<FunctionRegistry>,
+    format: &'a ValueFormat,
+    errors: &'a RefCell<Vec<String>>,
+    warnings: Option<&'a RefCell<Vec<RuntimeWarning>>>
+}
+
+impl<'a> StateManager for Scope<'a> {
+    fn get(&self, variable: &str) -> Option<&Value> {
+        let value = StateManager::get(self.state, variable);
+        if let (None, Some(warnings)) = (value, self.warnings) {
+            let warning = RuntimeWarning::UndefinedVariable {
+                page: self.state.current_page.to_string(),
+                variable: variable.to_string()
+            };
+            let mut warnings = warnings.borrow_mut();
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+        value
+    }
+
+    fn call(&self, function: &str, arguments: Vec<Value>) -> Value {
+        if function == "has_tag" {
+            return match arguments.first() {
+                Some(Value::Text(tag)) => Value::Boolean(self.story.page(&self.state.current_page, self.language)
+                    .is_some_and(|page| page.tags.iter().any(|t| t == tag))),
+                _ => Value::Null
+            };
+        }
+        match self.state.eval_function(function, &arguments) {
+            Some(Ok(value)) => return value,
+            Some(Err(error)) => {
+                self.errors.borrow_mut().push(error);
+                return Value::Null;
+            }
+            None => {}
+        }
+        match self.functions.borrow().call(function, &arguments) {
+            Some(Ok(value)) => return value,
+            Some(Err(error)) => {
+                self.errors.borrow_mut().push(error);
+                return Value::Null;
+            }
+            None => {}
+        }
+        match Value::eval_math(function, &arguments).or_else(|| Value::eval_conversion(function, &arguments)) {
+            Some(Ok(value)) => return value,
+            Some(Err(error)) => {
+                self.errors.borrow_mut().push(error);
+                return Value::Null;
+            }
+            None => {}
+        }
+        Value::eval_function(function, arguments).unwrap_or_else(|| {
+            self.errors.borrow_mut().push(format!("Unknown function: {}", function));
+            Value::Null
+        })
+    }
+
+    fn format(&self, value: &Value) -> String {
+        self.format.format(value)
+    }
+
+    fn error(&self, message: String) {
+        self.errors.borrow_mut().push(message);
+    }
+}
+
+// Called with the page and a snapshot of the state, hooks can't change the state.
+pub type PageHook = Box<dyn FnMut(&str, &StateSnapshot) + Send>;
+// Called with the dump_state JSON, see on_autosave.
+pub type AutosaveHook = Box<dyn FnMut(&str) + Send>;
+
+// Where play_streaming passes elements as they are produced, None when playing any other way.
+type Stream<'a> = Option<&'a mut dyn FnMut(Element)>;
+
+pub struct Interpreter {
+    story: Arc<Story>,
+    state: State,
+    history: VecDeque<State>,
+    history_limit: usize,
+    redirect_limit: usize,
+    loop_limit: usize,
+    output_limit: usize,
+    // Elements made since the last play() or send(), counted against the output limit.
+    produced: usize,
+    step_limit: usize,
+    metrics: ExecMetrics,
+    // Lent to the interpreter preview_page runs on.
+    functions: RefCell<FunctionRegistry>,
+    format: ValueFormat,
+    lenient_loading: bool,
+    trace: Option<Vec<TraceEvent>>,
+    warnings: Option<RefCell<Vec<RuntimeWarning>>>,
+    // Only set in strict mode, @assert is skipped otherwise.
+    assertions: Option<Vec<AssertionFailure>>,
+    language: Option<String>,
+    fallback_page: Option<String>,
+    // Set by with_seed, so restarts replay the same random choices.
+    seed: Option<u64>,
+    autosave: Option<AutosaveHook>,
+    enter_hooks: Vec<PageHook>,
+    exit_hooks: Vec<PageHook>,
+    errors: RefCell<Vec<String>>
+}
+
+impl Interpreter {
+    pub fn new(story: Story) ->  Self {
+        Self::from_shared(Arc::new(story))
+    }
+
+    // Interpreters made from the same story share it, each with its own state.
+    // Stories can be shared between threads, and each interpreter moved to the thread running it.
+    pub fn from_shared(story: Arc<Story>) -> Self {
+        let state = State::new(&story.first_page);
+        Interpreter {
+            story,
+            state,
+            history: VecDeque::new(),
+            history_limit: 100,
+            redirect_limit: 10_000,
+            loop_limit: 100_000,
+            output_limit: 100_000,
+            produced: 0,
+            step_limit: 10_000_000,
+            metrics: ExecMetrics::default(),
+            functions: RefCell::new(FunctionRegistry::new()),
+            format: ValueFormat::default(),
+            lenient_loading: false,
+            trace: None,
+            warnings: None,
+            assertions: None,
+            language: None,
+            fallback_page: None,
+            seed: None,
+            autosave: None,
+            enter_hooks: vec![],
+            exit_hooks: vec![],
+            errors: RefCell::new(vec![])
+        }
+    }
+
+    // Two interpreters with the same seed produce the same output for the same sequence of inputs.
+    pub fn with_seed(story: Story, seed: u64) -> Self {
+        let mut interpreter = Self::new(story);
+        interpreter.state.rng.set(seed);
+        interpreter.seed = Some(seed);
+        interpreter
+    }
+
+    // Maximum number of snapshots kept for back(), 0 disables history.
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self.history.truncate(limit);
+        self
+    }
+
+    // Maximum number of consecutive gotos followed by a single play().
+    pub fn with_redirect_limit(mut self, limit: usize) -> Self {
+        self.redirect_limit = limit;
+        self
+    }
+
+    // Lenient loading accepts saves from edited versions of the story: the story fingerprint
+    // isn't checked and a save on a page that no longer exists restarts from the first page.
+    pub fn with_lenient_loading(mut self, lenient: bool) -> Self {
+        self.lenient_loading = lenient;
+        self
+    }
+
+    // Formatting used when values are written into text.
+    pub fn with_format(mut self, format: ValueFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // Maximum number of iterations a single @for or @while may run.
+    pub fn set_loop_limit(&mut self, limit: usize) {
+        self.loop_limit = limit;
+    }
+
+    // Maximum number of elements a single play() or send() may produce, the rest are replaced by an error.
+    pub fn set_output_limit(&mut self, limit: usize) {
+        self.output_limit = limit;
+    }
+
+    // Maximum number of content nodes a single play() or send() may evaluate. Evaluation stops with
+    // an error once it is reached, gotos after it aren't followed.
+    pub fn set_step_limit(&mut self, limit: usize) {
+        self.step_limit = limit;
+    }
+
+    // Shown instead of an error when the page to show doesn't exist, such as a themed "lost" scene.
+    // If the fallback is missing too, or leads to another missing page, the error is shown.
+    pub fn set_fallback_page(&mut self, title: &str) {
+        self.fallback_page = Some(title.to_string());
+    }
+
+    pub fn last_metrics(&self) -> ExecMetrics {
+        self.metrics
+    }
+
+    // Called by every top level call, limits and metrics count from here.
+    fn begin(&mut self) {
+        self.produced = 0;
+        self.metrics = ExecMetrics::default();
+    }
+
+    // Pages are shown in this language when they have a translation, written as a page titled "Title@code".
+    // Takes effect from the next page shown, an empty code goes back to the pages without a language.
+    pub fn set_language(&mut self, code: &str) {
+        self.language = match code {
+            "" => None,
+            code => Some(code.to_string())
+        };
+    }
+
+    // Tracing records the execution path of the story, see take_trace().
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = if enabled { Some(self.trace.take().unwrap_or_default()) } else { None };
+    }
+
+    // Returns the events recorded since the last call.
+    pub fn take_trace(&mut self) -> Vec<TraceEvent> {
+        self.trace.as_mut().map_or(vec![], std::mem::take)
+    }
+
+    // Strict mode collects warnings about reads of undefined variables, see take_warnings(),
+    // and failed assertions, see take_assertion_failures().
+    pub fn set_strict(&mut self, strict: bool) {
+        self.warnings = if strict { Some(self.warnings.take().unwrap_or_default()) } else { None };
+        self.assertions = if strict { Some(self.assertions.take().unwrap_or_default()) } else { None };
+    }
+
+    // Returns the assertions that failed since the last call, in the order they ran.
+    pub fn take_assertion_failures(&mut self) -> Vec<AssertionFailure> {
+        self.assertions.as_mut().map_or(vec![], std::mem::take)
+    }
+
+    // Returns the warnings collected since the last call, without duplicates.
+    pub fn take_warnings(&mut self) -> Vec<RuntimeWarning> {
+        self.warnings.as_mut().map_or(vec![], |warnings| std::mem::take(warnings.get_mut()))
+    }
+
+    fn record(&mut self, event: impl FnOnce(String) -> TraceEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(event(self.state.current_page.to_string()));
+        }
+    }
+
+    // Called with the dump_state JSON after every play, send, back, restart and reload, so hosts can save
+    // the game without calling dump_state themselves. Rejected inputs and empty back() calls are skipped.
+    pub fn on_autosave(&mut self, hook: AutosaveHook) {
+        self.autosave = Some(hook);
+    }
+
+    fn autosave(&mut self) {
+        if self.autosave.is_none() {
+            return;
+        }
+        if let Some(json) = self.dump_state() {
+            if let Some(hook) = &mut self.autosave {
+                hook(&json);
+            }
+        }
+    }
+
+    // Called each time a page is played, before its content is evaluated.
+    pub fn on_page_enter(&mut self, hook: PageHook) {
+        self.enter_hooks.push(hook);
+    }
+
+    // Called when the player leaves a page through a link or a goto, before the next page is entered.
+    pub fn on_page_exit(&mut self, hook: PageHook) {
+        self.exit_hooks.push(hook);
+    }
+
+    fn fire_hooks(&mut self, exit: bool) {
+        let hooks = match exit {
+            true => &mut self.exit_hooks,
+            false => &mut self.enter_hooks
+        };
+        if hooks.is_empty() {
+            return;
+        }
+        let snapshot = self.state.snapshot();
+        for hook in hooks.iter_mut() {
+            hook(&snapshot.current_page, &snapshot);
+        }
+    }
+
+    pub fn register_fn(&mut self, name: &str, function: HostFunction) {
+        self.functions.get_mut().register(name, function);
+    }
+
+    pub fn register_fn_with_arity(&mut self, name: &str, arity: usize, function: HostFunction) {
+        self.functions.get_mut().register_with_arity(name, arity, function);
+    }
+
+    fn scope(&self) -> Scope<'_> {
+        Scope {
+            story: &self.story,
+            language: self.language.as_deref(),
+            state: &self.state,
+            functions: &self.functions,
+            format: &self.format,
+            errors: &self.errors,
+            warnings: self.warnings.as_ref()
+        }
+    }
+
+    fn loop_limit_error(&self, construct: &str) -> Element {
+        Element::Error(format!(
+            "Loop limit of {} iterations exceeded in @{} on page '{}'",
+            self.loop_limit, construct, self.state.current_page
+        ))
+    }
+
+    fn push_history(&mut self) {
+        if self.history_limit == 0 {
+            return;
+        }
+        if self.history.len() >= self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.state.clone());
+    }
+
+    // Undo the last send(), returns false if there is nothing to undo.
+    pub fn back(&mut self) -> bool {
+        if let Some(state) = self.history.pop_back() {
+            self.state = state;
+            self.autosave();
+            return true;
+        }
+        false
+    }
+
+    fn process_result(&mut self, result: StoryResult, index: usize) {
+        match result.action {
+            StoryAction::Goto(page) => {
+                self.fire_hooks(true);
+                self.state.current_page = page;
+                self.run(&mut None);
+                self.state.output.splice(0..0, result.output);
+            }
+            // A clear in an action removes the link and everything before it, the elements after it stay
+            // so their indices only shift by what came before.
+            _ if result.cleared => {
+                self.state.output.splice(0..index+1, result.output);
+            }
+            _ => {
+                self.state.output.splice(index..index+1, result.output);
+            }
+        }
+    }
+
+    pub fn send(&mut self, index: usize, mut value: Value) -> Result<(), SendError> {
+        match self.state.output.get_mut(index) {
+            None => return Err(SendError::IndexOutOfRange(index)),
+            Some(element) if !element.is_interactive() => return Err(SendError::NotInteractive(index)),
+            Some(Element::Input(_, _, _)) if matches!(value, Value::Null) => return Err(SendError::MissingInput),
+            // Forms take an object with a value for each field.
+            Some(Element::Form { .. }) => match value {
+                Value::Object(values) => return self.submit(index, values),
+                _ => return Err(SendError::MissingInput)
+            },
+            // Rejected values show the input again with the error instead of running its action.
+            Some(Element::Input(_, _, spec)) => match spec.validate(value) {
+                Ok(valid) => value = valid,
+                Err(error) => {
+                    spec.error = Some(error.to_string());
+                    return Err(SendError::InvalidInput(error));
+                }
+            },
+            _ => {}
+        }
+        self.push_history();
+        self.begin();
+        // Only what the action needs is copied out of the element, its content comes with the action.
+        match &self.state.output[index] {
+            Element::Link(_, destination) => {
+                let destination = destination.to_string();
+                self.fire_hooks(true);
+                self.state.current_page = destination;
+                self.run(&mut None);
+            }
+            Element::ContentLink(_, action) => {
+                let action = action.clone();
+                self.state.consume(&action);
+                if let Some(content) = self.story.get_action(&action) {
+                    let result = self.eval(&content, &mut None);
+                    self.process_result(result, index);
+                }
+            }
+            Element::JumpLink(_, destination, action, fallback) => {
+                let (destination, action, fallback) = (destination.to_string(), action.clone(), fallback.clone());
+                self.state.consume(&action);
+                if let Some(content) = self.story.get_action(&action) {
+                    let mut result = self.eval(&content, &mut None);
+                    // A goto in the link's content takes the reader elsewhere instead. Without a return
+                    // the link goes to its first destination.
+                    result.action = match (result.action, fallback) {
+                        (StoryAction::Goto(page), _) => StoryAction::Goto(page),
+                        (StoryAction::Return(value), Some(fallback)) if !value.is_true() => StoryAction::Goto(fallback),
+                        _ => StoryAction::Goto(destination)
+                    };
+                    self.process_result(result, index);
+                }
+            }
+            // Glossary terms toggle their definition without leaving the page.
+            Element::Glossary { term, page, expanded } => {
+                let (term, page, expanded) = (term.to_string(), page.to_string(), *expanded);
+                let expanded = match expanded {
+                    Some(_) => {
+                        let end = self.glossary_end(index);
+                        self.state.output.drain(index + 1..end);
+                        None
+                    }
+                    None => {
+                        let definition = self.define(&page);
+                        let count = definition.len();
+                        self.state.output.splice(index + 1..index + 1, definition);
+                        Some(count)
+                    }
+                };
+                self.state.output[index] = Element::Glossary { term, page, expanded };
+            }
+            Element::Input(variable, action, _) => {
+                let (variable, content) = (variable.to_string(), self.story.get_action(action));
+                if let Some(content) = content {
+                    let mut result = StoryResult::new();
+                    self.set_local(&mut result, &variable, value, &mut None);
+                    let content_result = self.eval(&content, &mut None);
+                    result.combine(content_result);
+                    self.process_result(result, index);
+                }
+            }
+            _ => {}
+        }
+        self.autosave();
+        Ok(())
+    }
+
+    // Sets every field of a form and runs its action, values without a field are ignored. Nothing is set
+    // unless every field has a valid value, otherwise the form is shown again with the error of each field.
+    pub fn submit(&mut self, index: usize, values: HashMap<String, Value>) -> Result<(), SendError> {
+        let (title, action, mut fields) = match self.state.output.get(index) {
+            None => return Err(SendError::IndexOutOfRange(index)),
+            Some(Element::Form { title, action, fields }) => (title.to_string(), action.clone(), fields.clone()),
+            Some(_) => return Err(SendError::NotAForm(index))
+        };
+        let mut valid = vec![];
+        let mut error = None;
+        for (variable, spec) in fields.iter_mut() {
+            let result = match values.get(variable) {
+                None | Some(Value::Null) => Err(SendError::MissingInput),
+                Some(value) => spec.validate(value.clone()).map_err(SendError::InvalidInput)
+            };
+            spec.error = None;
+            match result {
+                Ok(value) => valid.push((variable.to_string(), value)),
+                Err(field_error) => {
+                    spec.error = Some(match &field_error {
+                        SendError::InvalidInput(message) => message.to_string(),
+                        _ => "Expected a value".to_string()
+                    });
+                    error.get_or_insert(field_error);
+                }
+            }
+        }
+        if let Some(error) = error {
+            self.state.output[index] = Element::Form { title, action, fields };
+            return Err(error);
+        }
+        self.push_history();
+        self.begin();
+        if let Some(content) = self.story.get_action(&action) {
+            let mut result = StoryResult::new();
+            for (variable, value) in valid {
+                self.set_local(&mut result, &variable, value, &mut None);
+            }
+            let content_result = self.eval(&content, &mut None);
+            result.combine(content_result);
+            self.process_result(result, index);
+        }
+        self.autosave();
+        Ok(())
+    }
+
+    // The @setup blocks of the pages a page is nested in run before its content, outermost first,
+    // with the variables of the page being played.
+    fn eval_setup(&mut self, title: &str, stream: &mut Stream) -> StoryResult {
+        let story = Arc::clone(&self.story);
+        let mut result = StoryResult::new();
+        for ancestor in story.ancestors(title) {
+            let setup = story.page(ancestor, self.language.as_deref()).map_or(vec![], |page| page.setup());
+            for content in setup {
+                if !matches!(result.action, StoryAction::Halt) {
+                    return result;
+                }
+                let content_result = self.eval(content, stream);
+                result.combine(content_result);
+            }
+        }
+        result
     }
 
-    fn combine(&mut self, mut result: StoryResult) {
-        self.output.append(&mut result.output);
-        self.action = result.action;
+    // Starting at a label skips the content before it, including its @set lines. Only the @setup
+    // blocks before the label still run.
+    fn eval_page(&mut self, page: &Page, label: Option<&str>, stream: &mut Stream) -> StoryResult {
+        let label = match label {
+            Some(label) => label,
+            None => return self.eval(&page.content, stream)
+        };
+        let start = match page.label(label) {
+            Some(start) => start,
+            None => {
+                let mut result = StoryResult::new();
+                let error = format!("Label '{}' not found on page '{}'", label, self.state.current_page);
+                self.emit(&mut result, Element::Error(error), stream);
+                return result;
+            }
+        };
+        let mut result = StoryResult::new();
+        for content in &page.content[..start] {
+            if let (Content::Setup { content }, StoryAction::Halt) = (content, &result.action) {
+                let content_result = self.eval(content, stream);
+                result.combine(content_result);
+            }
+        }
+        if let StoryAction::Halt = result.action {
+            let content_result = self.eval(&page.content[start..], stream);
+            result.combine(content_result);
+        }
+        result
     }
 
-    fn push(&mut self, element: Element) {
-        self.output.push(element);
+    // The content of a glossary page, evaluated like a scoped import. Gotos in it are ignored.
+    fn define(&mut self, title: &str) -> Vec<Element> {
+        let story = Arc::clone(&self.story);
+        let page = match story.page(title, self.language.as_deref()) {
+            Some(page) => page,
+            None => return vec![Element::Error(format!("Invalid page: '{}'", title))]
+        };
+        let caller = std::mem::replace(&mut self.state.current_page, title.to_string());
+        let result = self.eval(&page.content, &mut None);
+        self.state.current_page = caller;
+        result.output
     }
-}
 
-pub struct Story {
-    first_page: String,
-    pages: HashMap<String, Page>
-}
+    // The output a page would show with the current state, for previews. The page runs on a copy of
+    // the state, so changes it makes, such as variables it sets, are discarded and gotos in it aren't
+    // followed. Host functions can still be called, hooks and autosave aren't.
+    pub fn preview_page(&self, title: &str) -> Vec<Element> {
+        let story = Arc::clone(&self.story);
+        let mut preview = Interpreter::from_shared(Arc::clone(&story));
+        preview.state = self.state.clone();
+        preview.state.current_page = title.to_string();
+        preview.loop_limit = self.loop_limit;
+        preview.output_limit = self.output_limit;
+        preview.step_limit = self.step_limit;
+        preview.format = self.format.clone();
+        preview.language = self.language.clone();
+        preview.functions = RefCell::new(self.functions.take());
+        let output = match story.page(title, self.language.as_deref()) {
+            Some(page) => {
+                let mut result = preview.eval_setup(title, &mut None);
+                if let StoryAction::Halt = result.action {
+                    let content_result = preview.eval(&page.content, &mut None);
+                    result.combine(content_result);
+                }
+                result.output
+            }
+            None => vec![Element::Error(format!("Invalid page: '{}'", title))]
+        };
+        self.functions.replace(preview.functions.into_inner());
+        output
+    }
 
-pub enum StoryError {
-    Content(ContentError, String, usize),
-    DuplicatePage(String, usize)
-}
+    // The output send() would show, without changing the interpreter. The choice runs on a copy of
+    // the state that is then discarded, so variables it sets, visits and consumed links are not kept.
+    // Hooks and autosave aren't called, host functions are.
+    pub fn peek_send(&mut self, index: usize, value: Value) -> Result<Vec<Element>, SendError> {
+        let state = self.state.clone();
+        let history = std::mem::take(&mut self.history);
+        let trace = self.trace.take();
+        let warnings = self.warnings.take();
+        let assertions = self.assertions.take();
+        let metrics = std::mem::take(&mut self.metrics);
+        let autosave = self.autosave.take();
+        let enter_hooks = std::mem::take(&mut self.enter_hooks);
+        let exit_hooks = std::mem::take(&mut self.exit_hooks);
+        let result = self.send(index, value);
+        let output = std::mem::replace(&mut self.state, state).output;
+        self.history = history;
+        self.trace = trace;
+        self.warnings = warnings;
+        self.assertions = assertions;
+        self.metrics = metrics;
+        self.autosave = autosave;
+        self.enter_hooks = enter_hooks;
+        self.exit_hooks = exit_hooks;
+        result.map(|_| output)
+    }
 
-impl fmt::Display for StoryError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            StoryError::Content(error, page, line) =>
-                write!(f, "Parsing error on page '{}', line {}:\n{}", page, line, error),
-            StoryError::DuplicatePage(page, line) =>
-                write!(f, "Duplicate page '{}' on line {}", page, line)
+    // The position after the element at index and the definitions shown after it,
+    // including those of expanded terms inside them.
+    fn glossary_end(&self, index: usize) -> usize {
+        let mut end = index + 1;
+        if let Some(Element::Glossary { expanded: Some(count), .. }) = self.state.output.get(index) {
+            for _ in 0..*count {
+                end = self.glossary_end(end);
+            }
         }
+        end.min(self.state.output.len())
     }
-}
 
-impl Story {
-    pub fn new(source: &str) -> Result<Self, StoryError> {
-        lazy_static! {
-            static ref HEADER_REGEX: Regex = Regex::new(r"^#+(?P<title>.+)").unwrap();
-        }
+    pub fn play(&mut self) {
+        self.begin();
+        self.run(&mut None);
+        self.autosave();
+    }
 
-        let mut pages = HashMap::<String, Page>::new();
-        let mut content_acumulator = "".to_string();
-        let mut first_page: Option<&str> = None;
-        let mut current_page: Option<&str> = None;
+    // Plays like play(), passing every element to the sink as soon as it is produced. The sink only
+    // observes: elements of pages left with a @goto, or removed by @clear, are streamed too, and
+    // output() is the same as after play(), in the order the elements were streamed.
+    pub fn play_streaming(&mut self, mut sink: impl FnMut(Element)) {
+        self.begin();
+        self.run(&mut Some(&mut sink));
+        self.autosave();
+    }
 
-        let mut page_line: usize = 1;
-        for (line_number, line) in source.lines().enumerate() {
-            if let Some(capture) = HEADER_REGEX.captures(line) {
-                if let Some(title) = current_page {
-                    let page = Self::parse_page(page_line, title, &content_acumulator)?;
-                    pages.insert(title.to_string(), page);
-                    content_acumulator = "".to_string();
-                }
-                page_line = line_number + 1;
-                let title = capture.name("title").unwrap().as_str().trim();
-                if pages.contains_key(title) {
-                    return Err(StoryError::DuplicatePage(title.to_string(), page_line));
+    fn run(&mut self, stream: &mut Stream) {
+        self.state.output.clear();
+        let story: &Story = &Arc::clone(&self.story);
+        let mut path: Vec<String> = vec![];
+        let mut fell_back = false;
+        loop {
+            let (title, label) = story.split_label(&self.state.current_page);
+            let label = label.map(str::to_string);
+            self.state.current_page = title.to_string();
+            if let Some(page) = story.page(&self.state.current_page, self.language.as_deref()) {
+                *self.state.visits.entry(self.state.current_page.to_string()).or_insert(0) += 1;
+                self.metrics.pages += 1;
+                self.state.enter(&self.state.current_page.to_string());
+                self.record(|page| TraceEvent::PageEntered { page });
+                self.fire_hooks(false);
+                let mut result = self.eval_setup(&self.state.current_page.to_string(), stream);
+                if let StoryAction::Halt = result.action {
+                    let content_result = self.eval_page(page, label.as_deref(), stream);
+                    result.combine(content_result);
                 }
-                if first_page == None {
-                    first_page = current_page
+                // Only the page the reader lands on keeps its output, it becomes the output as it is.
+                match result.action {
+                    StoryAction::Goto(p) if !self.metrics.step_limit_reached => {
+                        self.record(|page| TraceEvent::Goto { page, destination: p.to_string() });
+                        path.push(self.state.current_page.to_string());
+                        self.fire_hooks(true);
+                        self.state.current_page = p;
+                        if path.len() > self.redirect_limit {
+                            let error = Self::goto_loop_error(&path, &self.state.current_page);
+                            Self::stream(stream, &error);
+                            self.state.output.push(error);
+                            break;
+                        }
+                    }
+                    _ => {
+                        self.state.output = result.output;
+                        break;
+                    }
                 }
-                current_page = Some(title);
-            } else if let Some(_) = current_page {
-                content_acumulator += &format!("{}\n", line);
             }
-        }
-        if let Some(title) = current_page {
-            let page = Self::parse_page(page_line, title, &content_acumulator)?;
-            pages.insert(title.to_string(), page);
-            if first_page == None {
-                first_page = Some(title)
+            else if let Some(fallback) = self.fallback_page.as_ref().filter(|fallback| {
+                !fell_back && story.page(fallback, self.language.as_deref()).is_some()
+            }) {
+                fell_back = true;
+                self.state.current_page = fallback.to_string();
+            }
+            else {
+                let error = Element::Error(format!("Invalid page: '{}'", self.state.current_page));
+                Self::stream(stream, &error);
+                self.state.output.push(error);
+                break;
             }
         }
-        Ok(Story {pages, first_page: first_page.unwrap_or("").to_string()})
     }
 
-    fn parse_page(line_number: usize, title: &str, content: &str) -> Result<Page, StoryError> {
-        Page::parse(title, content).map_err(|(size, error)| StoryError::Content (
-            error,
-            title.to_string(),
-            line_number + content[..size].lines().count()
-        ))
+    fn goto_loop_error(path: &[String], page: &str) -> Element {
+        let start = path.iter().rposition(|p| p == page).unwrap_or(0);
+        let mut cycle: Vec<&str> = path[start..].iter().map(|p| p.as_str()).collect();
+        cycle.push(page);
+        Element::Error(format!("Goto loop detected: {}", cycle.join(" -> ")))
     }
 
-    fn get_action(&self, action: PageAction) -> Option<&Vec<Content>> {
-        let page = self.pages.get(&action.page)?;
-        return page.actions.get(action.index);
+    // The pages visited to reach the current page, starting with the first one and ending with the current one.
+    pub fn path(&self) -> &[String] {
+        &self.state.path
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct State {
-    current_page: String,
-    global: HashMap<String, Value>,
-    local: HashMap<String, HashMap<String, Value>>,
-    output: Vec<Element>
-}
+    pub fn output(&self) -> &Vec<Element> {
+        &self.state.output
+    }
 
-impl State {
-    fn new(first_page: &str) -> State {
-        State {
-            current_page: first_page.to_string(),
-            global: HashMap::new(),
-            local: HashMap::new(),
-            output: vec![]
-        }
+    // The output as OutputElements, with indices set on interactive elements.
+    pub fn output_elements(&self) -> Vec<OutputElement> {
+        (0..self.state.output.len()).filter_map(|index| self.output_element(index)).collect()
     }
 
-    fn get(&self, page: &str, variable: &str) -> Option<&Value> {
-        if let Some(state) = self.local.get(page) {
-            if let Some(value) = state.get(variable) {
-                return Some(value);
-            }
+    // A single element of output_elements(), None if the index is out of range.
+    pub fn output_element(&self, index: usize) -> Option<OutputElement> {
+        let element = self.state.output.get(index)?;
+        let mut output = OutputElement::from(element);
+        if element.is_interactive() {
+            output.index = Some(index);
         }
-        return self.global.get(variable);
+        Some(output)
     }
 
-    fn set(&mut self, variable: &str, value: Value) {
-        self.global.insert(variable.to_string(), value);
+    // Interactive elements in output order, along with their index in output() to pass to send().
+    pub fn choices(&self) -> Vec<(usize, &Element)> {
+        self.state.output.iter()
+            .enumerate()
+            .filter(|(_, element)| element.is_interactive())
+            .collect()
     }
 
-    fn set_index(&mut self, variable: &str, indices: &Vec<Value>, value: Value) {
-        if indices.is_empty() {
-            return self.set(variable, value);
+    // True when the reader has no choice left to make, the story reached an ending. Glossary terms
+    // only show their definition, so they don't keep a story going.
+    pub fn is_finished(&self) -> bool {
+        !self.state.output.iter().any(|element| element.is_interactive() && !matches!(element, Element::Glossary { .. }))
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        self.state.snapshot()
+    }
+
+    pub fn dump_state(&self) -> Option<String> {
+        if let Ok(json) = serde_json::to_string(&self.saved_game()) {
+            return Some(json)
         }
-        if let Some(var) = self.global.get_mut(variable) {
-            if let Some(reference) = var.get_mut(indices) {
-                *reference = value;
-            }
+        None
+    }
+
+    // The same save as dump_state in a smaller binary form, for hosts with limited storage.
+    pub fn dump_state_compact(&self) -> Option<Vec<u8>> {
+        if let Ok(value) = serde_json::to_value(self.saved_game()) {
+            return Some(compact::encode(&value))
         }
+        None
     }
 
-    fn set_local(&mut self, variable: &str, value: Value) -> Option<()> {
-        let state = match self.local.get_mut(&self.current_page) {
-            Some(state) => state,
-            None => {
-                self.local.insert(self.current_page.to_string(), HashMap::new());
-                self.local.get_mut(&self.current_page)?
+    fn saved_game(&self) -> SavedGame {
+        SavedGame {
+            version: SAVE_VERSION,
+            fingerprint: Some(self.story.fingerprint()),
+            state: self.state.clone()
+        }
+    }
+
+    pub fn load_state_compact(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        let value = compact::decode(data).map_err(LoadError::Compact)?;
+        let saved = serde_json::from_value::<SavedGame>(value).map_err(LoadError::Json)?;
+        self.load_saved(saved)
+    }
+
+    pub fn load_state(&mut self, json: &str) -> Result<(), LoadError> {
+        let saved = match serde_json::from_str::<SavedGame>(json) {
+            Ok(saved) => saved,
+            // Saves made before versioning only hold the state.
+            Err(error) => match serde_json::from_str::<State>(json) {
+                Ok(state) => SavedGame { version: 0, fingerprint: None, state },
+                Err(_) => return Err(LoadError::Json(error))
             }
         };
-        state.insert(variable.to_string(), value);
-        Some(())
+        self.load_saved(saved)
     }
 
-    fn set_local_index(&mut self, variable: &str, indices: &Vec<Value>, value: Value) -> Option<()> {
-        if indices.is_empty() {
-            return self.set_local(variable, value);
+    fn load_saved(&mut self, saved: SavedGame) -> Result<(), LoadError> {
+        if saved.version > SAVE_VERSION {
+            return Err(LoadError::Version(saved.version));
+        }
+        match saved.fingerprint {
+            Some(fingerprint) if !self.lenient_loading && fingerprint != self.story.fingerprint() => {
+                return Err(LoadError::DifferentStory);
+            }
+            _ => {}
+        }
+        let missing_page = self.story.page(&saved.state.current_page, self.language.as_deref()).is_none();
+        if missing_page && !self.lenient_loading {
+            return Err(LoadError::MissingPage(saved.state.current_page));
         }
-        let state = self.local.get_mut(&self.current_page)?;
-        let var = state.get_mut(variable)?;
-        let reference = var.get_mut(indices)?;
-        Some(*reference = value)
+        self.state = saved.state;
+        self.history.clear();
+        if missing_page {
+            self.state.current_page = self.story.first_page.to_string();
+            self.begin();
+            self.run(&mut None);
+        }
+        Ok(())
     }
-}
 
-impl StateManager for State {
-    fn get(&self, variable: &str) -> Option<&Value> {
-        self.get(&self.current_page, variable)
+    // Starts the story over from the first page, with no variables, visits or history.
+    pub fn restart(&mut self) {
+        self.state = State::new(&self.story.first_page);
+        if let Some(seed) = self.seed {
+            self.state.rng.set(seed);
+        }
+        self.history.clear();
+        self.play();
     }
-}
-
-pub struct Interpreter {
-    story: Rc<Story>,
-    state: State
-}
 
-impl Interpreter {
-    pub fn new(story: Story) ->  Self {
-        let state = State::new(&story.first_page);
-        Interpreter {
-            story: Rc::new(story),
-            state,
+    // Replaces the story while keeping the state, then plays the current page again.
+    // Undo history is dropped, since it was recorded against the old story.
+    pub fn reload(&mut self, story: Story) {
+        self.story = Arc::new(story);
+        self.history.clear();
+        let missing = match self.story.page(&self.state.current_page, self.language.as_deref()).is_some() {
+            true => None,
+            false => Some(std::mem::replace(&mut self.state.current_page, self.story.first_page.to_string()))
+        };
+        self.begin();
+        self.run(&mut None);
+        if let Some(page) = missing {
+            let warning = format!("Page '{}' no longer exists, restarted from '{}'", page, self.story.first_page);
+            self.state.output.insert(0, Element::Error(warning));
         }
+        self.autosave();
     }
 
-    fn process_result(&mut self, result: StoryResult, index: usize) {
-        match result.action {
-            StoryAction::Halt => {
-                self.state.output.splice(index..index+1, result.output);
-            }
-            StoryAction::Goto(page) => {
-                self.state.current_page = page;
-                self.play();
-                self.state.output.splice(0..0, result.output);
+    // Each block of content gets its own frame for @let variables, dropped when the block ends.
+    fn input_spec(&self, kind: Option<InputType>, constraints: &[(String, Expression)]) -> InputSpec {
+        let mut spec = InputSpec { kind, ..InputSpec::default() };
+        for (name, expression) in constraints.iter() {
+            let value = expression.eval(&self.scope());
+            match (name.as_str(), value) {
+                ("min", value) => spec.min = Some(value),
+                ("max", value) => spec.max = Some(value),
+                ("prompt" | "placeholder", Value::Null) => {}
+                ("prompt", value) => spec.prompt = Some(value.to_string()),
+                ("placeholder", value) => spec.placeholder = Some(value.to_string()),
+                (_, value) => spec.pattern = Some(value.to_string())
             }
         }
+        spec
     }
 
-    pub fn send(&mut self, index: usize, value: Value) {
-        let element: Option<Element> = self.state.output.get(index).cloned();
-        let story = &Rc::clone(&self.story);
-        if let Some(Element::Link(_, destination)) = element {
-            self.state.current_page = destination.to_string();
-            self.play();
+    fn stream(stream: &mut Stream, element: &Element) {
+        if let Some(stream) = stream {
+            stream(element.clone());
         }
-        else if let Some(Element::ContentLink(_, action)) = element {
-            if let Some(content) = story.get_action(action) {
-                let result = self.eval(content);
-                self.process_result(result, index);
-            }
-        }
-        else if let Some(Element::JumpLink(_, destination, action)) = element {
-            if let Some(content) = story.get_action(action) {
-                let mut result = self.eval(content);
-                result.action = StoryAction::Goto(destination.to_string());
-                self.process_result(result, index);
-            }
+    }
+
+    // Counts an element against the output limit. The element going over it is replaced by an error
+    // and the ones after it are dropped.
+    fn limit(&mut self, element: Element) -> Option<Element> {
+        self.produced += 1;
+        if self.produced <= self.output_limit {
+            return Some(element);
         }
-        else if let Some(Element::Input(variable, action)) = element {
-            if let Some(content) = story.get_action(action) {
-                self.state.set_local(&variable, value);
-                let result = self.eval(content);
-                self.process_result(result, index);
-            }
+        if self.produced == self.output_limit + 1 {
+            return Some(Element::Error("Output limit reached".to_string()));
         }
+        None
     }
 
-    pub fn play(&mut self) {
-        self.state.output.clear();
-        let story: &Story = &Rc::clone(&self.story);
-        loop {
-            if let Some(page) = story.pages.get(&self.state.current_page) {
-                let mut result = self.eval(&page.content);
-                self.state.output.append(&mut result.output);
-                match result.action {
-                    StoryAction::Halt => break,
-                    StoryAction::Goto(p) => {
-                        self.state.output.clear();
-                        self.state.current_page = p
-                    }
-                }
-            }
-            else {
-                self.state.output.push(Element::Error(format!("Invalid page: '{}'", self.state.current_page)));
-                break;
-            }
+    fn set_local(&mut self, result: &mut StoryResult, variable: &str, value: Value, stream: &mut Stream) {
+        if self.state.set_local(variable, value).is_none() {
+            self.emit(result, Element::Error(format!("Cannot assign to constant {}", variable)), stream);
         }
     }
 
-    pub fn output(&self) -> &Vec<Element> {
-        &self.state.output
+    // Elements are added to a result here, so they reach the stream as soon as they are made. Errors
+    // found while making the element come before it.
+    fn emit(&mut self, result: &mut StoryResult, element: Element, stream: &mut Stream) {
+        self.emit_errors(result, stream);
+        if let Some(element) = self.limit(element) {
+            Self::stream(stream, &element);
+            result.push(element);
+        }
     }
 
-    pub fn dump_state(&self) -> Option<String> {
-        if let Ok(json) = serde_json::to_string(&self.state) {
-            return Some(json)
+    fn emit_errors(&mut self, result: &mut StoryResult, stream: &mut Stream) {
+        let errors: Vec<_> = self.errors.borrow_mut().drain(..).collect();
+        for error in errors {
+            if let Some(error) = self.limit(Element::Error(error)) {
+                Self::stream(stream, &error);
+                result.push(error);
+            }
         }
-        None
     }
 
-	pub fn load_state(&mut self, json: &str) -> serde_json::Result<()> {
-		self.state = serde_json::from_str(json)?;
-		Ok(())
-	}
+    fn eval(&mut self, content: &[Content], stream: &mut Stream) -> StoryResult {
+        self.state.blocks.push((self.state.current_page.to_string(), HashMap::new()));
+        let result = self.eval_block(content, stream);
+        self.state.blocks.pop();
+        result
+    }
 
-    fn eval(&mut self, content: &Vec<Content>) -> StoryResult {
+    fn eval_block(&mut self, content: &[Content], stream: &mut Stream) -> StoryResult {
         let mut result = StoryResult::new();
-        let mut if_action: Option<bool> = None;
-        let story: &Story = &Rc::clone(&self.story);
+        let story: &Story = &Arc::clone(&self.story);
         for element in content.iter() {
+            if self.metrics.nodes >= self.step_limit {
+                if !self.metrics.step_limit_reached {
+                    self.metrics.step_limit_reached = true;
+                    let error = format!("Step limit of {} exceeded on page '{}'", self.step_limit, self.state.current_page);
+                    self.emit(&mut result, Element::Error(error), stream);
+                }
+                break;
+            }
+            self.metrics.nodes += 1;
             match element {
-                Content::Text(s) => result.push(Element::Text(s.eval(&self.state))),
-                Content::Link(link) => {
+                Content::Text(s) => {
+                    let spans = s.eval_spans(&self.scope());
+                    match spans.iter().all(Span::is_plain) {
+                        true => self.emit(&mut result, Element::Text(spans.into_iter().map(|span| span.text).collect()), stream),
+                        false => self.emit(&mut result, Element::StyledText(spans), stream)
+                    }
+                }
+                Content::Link { action: link, guard } => {
+                    let visible = guard.as_ref().is_none_or(|expression| expression.eval(&self.scope()).is_true());
                     let element = match link {
+                        _ if !visible => None,
+                        Action::Content{action, ..} | Action::JumpLink{action, ..} if self.state.is_consumed(action) => None,
                         Action::Normal{title, destination} => {
-                            Element::Link(title.eval(&self.state), destination.eval(&self.state))
+                            let destination = story.resolve(&self.state.current_page, &destination.eval(&self.scope()));
+                            Some(Element::Link(title.eval(&self.scope()), destination))
                         }
                         Action::Content{title, action} => {
-                            Element::ContentLink(title.eval(&self.state), action.clone())
+                            Some(Element::ContentLink(title.eval(&self.scope()), story.resolved(action)))
                         }
-                        Action::JumpLink{title, destination, action} => {
-                            Element::JumpLink(title.eval(&self.state), destination.eval(&self.state), action.clone())
+                        Action::JumpLink{title, destination, fallback, action} => {
+                            let destination = story.resolve(&self.state.current_page, &destination.eval(&self.scope()));
+                            let fallback = fallback.as_ref().map(|fallback| story.resolve(&self.state.current_page, &fallback.eval(&self.scope())));
+                            Some(Element::JumpLink(title.eval(&self.scope()), destination, story.resolved(action), fallback))
                         }
-                        Action::Input{variable, action} => {
-                            Element::Input(variable.to_string(), action.clone())
+                        Action::Input{variable, action, kind, constraints} => {
+                            Some(Element::Input(variable.to_string(), story.resolved(action), self.input_spec(*kind, constraints)))
                         }
+                        Action::Form{title, action, fields} => {
+                            let fields = fields.iter().map(|(variable, kind, constraints)| {
+                                (variable.to_string(), self.input_spec(*kind, constraints))
+                            }).collect();
+                            Some(Element::Form { title: title.eval(&self.scope()), action: story.resolved(action), fields })
+                        }
+                    };
+                    if let Some(element) = element {
+                        self.emit(&mut result, element, stream);
+                    }
+                }
+                Content::Field { .. } => self.emit(&mut result, Element::Error("@field without a matching @submit".to_string()), stream),
+                Content::Image { alt, src } => {
+                    self.emit(&mut result, Element::Image { src: src.eval(&self.scope()), alt: alt.eval(&self.scope()) }, stream);
+                }
+                Content::Audio { src, looping, channel } => {
+                    let looping = looping.as_ref().is_some_and(|looping| looping.eval(&self.scope()).is_true());
+                    let channel = match channel.as_ref().map(|channel| channel.eval(&self.scope())) {
+                        None | Some(Value::Null) => None,
+                        Some(channel) => Some(channel.to_string())
                     };
-                    result.push(element);
+                    self.emit(&mut result, Element::Audio { src: src.eval(&self.scope()), looping, channel }, stream);
+                }
+                Content::Wait(expression) => match expression.eval(&self.scope()) {
+                    Value::Integer(ms) => self.emit(&mut result, Element::Delay { ms: ms.clamp(0, u32::MAX as i64) as u32 }, stream),
+                    Value::Float(ms) if ms.is_finite() => self.emit(&mut result, Element::Delay { ms: ms.round().clamp(0.0, u32::MAX as f64) as u32 }, stream),
+                    _ => self.emit(&mut result, Element::Error("@wait time must be a number".to_string()), stream)
+                },
+                Content::Assert { condition, message } if self.assertions.is_some() => {
+                    if !condition.eval(&self.scope()).is_true() {
+                        let message = message.as_ref().map_or("Assertion failed".to_string(), |message| {
+                            self.scope().format(&message.eval(&self.scope()))
+                        });
+                        let failure = AssertionFailure { page: self.state.current_page.to_string(), message };
+                        self.assertions.get_or_insert_with(Vec::new).push(failure);
+                    }
+                }
+                // Outside strict mode assertions cost nothing, the condition isn't evaluated.
+                Content::Assert { .. } => {}
+                Content::Goto(page) => {
+                    result.action = StoryAction::Goto(story.resolve(&self.state.current_page, &page.eval(&self.scope())));
                 }
-                Content::Goto(page) => {result.action = StoryAction::Goto(page.eval(&self.state))},
-                Content::Import(page_title) => {
-                    if let Some(page) = story.pages.get(&page_title.eval(&self.state)) {
-                        let import_result = self.eval(&page.content);
+                Content::Import { page: page_title, scoped, arguments, result: variable } => {
+                    let title = story.resolve(&self.state.current_page, &page_title.eval(&self.scope()));
+                    self.record(|page| TraceEvent::Import { page, imported: title.to_string() });
+                    if let Some(page) = story.page(&title, self.language.as_deref()) {
+                        let mut arguments: HashMap<String, Value> = arguments.iter()
+                            .map(|(name, expression)| (name.to_string(), expression.eval(&self.scope())))
+                            .collect();
+                        let mut import_result = if *scoped {
+                            for name in arguments.keys().filter(|name| self.state.constants.contains(*name)).cloned().collect::<Vec<_>>() {
+                                arguments.remove(&name);
+                                self.emit(&mut result, Element::Error(format!("Cannot assign to constant {}", name)), stream);
+                            }
+                            // Local variables are keyed by the current page, globals are shared.
+                            let caller = std::mem::replace(&mut self.state.current_page, title.to_string());
+                            let saved = self.state.local.insert(title.to_string(), arguments);
+                            let import_result = self.eval(&page.content, stream);
+                            self.state.current_page = caller;
+                            match saved {
+                                Some(saved) => self.state.local.insert(title, saved),
+                                None => self.state.local.remove(&title)
+                            };
+                            import_result
+                        } else {
+                            for (name, value) in arguments {
+                                self.set_local(&mut result, &name, value, stream);
+                            }
+                            self.eval(&page.content, stream)
+                        };
+                        // A return ends the imported page, not the caller.
+                        if let StoryAction::Return(value) = import_result.action {
+                            import_result.action = StoryAction::Halt;
+                            if let Some(variable) = variable {
+                                self.set_local(&mut result, variable, value, stream);
+                            }
+                        }
                         result.combine(import_result);
                     }
                 }
+                Content::Glossary { term, page } => {
+                    let page = story.resolve(&self.state.current_page, &page.eval(&self.scope()));
+                    self.emit(&mut result, Element::Glossary { term: term.eval(&self.scope()), page, expanded: None }, stream);
+                }
                 Content::Set{local, variable, indices, expression} => {
-                    let value = expression.eval(&self.state);
-                    let ind: Vec<_> = indices.iter().map(|x| x.eval(&self.state)).collect();
-                    if *local {
-                        self.state.set_local_index(variable, &ind, value);
+                    let value = expression.eval(&self.scope());
+                    let ind: Vec<_> = indices.iter().map(|x| x.eval(&self.scope())).collect();
+                    self.record(|page| TraceEvent::VariableSet {
+                        page, variable: variable.to_string(), local: *local, value: value.clone()
+                    });
+                    let assigned = if *local {
+                        self.state.set_local_index(variable, &ind, value)
                     }
                     else {
-                        self.state.set_index(variable, &ind, value);
+                        self.state.set_index(variable, &ind, value)
+                    };
+                    if assigned.is_none() && self.state.constants.contains(variable) {
+                        self.emit(&mut result, Element::Error(format!("Cannot assign to constant {}", variable)), stream);
+                    }
+                    else if assigned.is_none() {
+                        let indices: Vec<_> = ind.iter().map(|index| format!("[{}]", index)).collect();
+                        self.emit(&mut result, Element::Error(format!("Invalid assignment to {}{}", variable, indices.join(""))), stream);
                     }
                 }
-                Content::If{expression, content} => {
-                    if_action = Some(expression.eval(&self.state).is_true());
-                    if let Some(true) = if_action {
-                        let content_result = self.eval(content);
-                        result.combine(content_result);
+                Content::Setup { content } => {
+                    let content_result = self.eval(content, stream);
+                    result.combine(content_result);
+                }
+                Content::Let { variable, expression } => {
+                    let value = expression.eval(&self.scope());
+                    self.record(|page| TraceEvent::VariableSet {
+                        page, variable: variable.to_string(), local: true, value: value.clone()
+                    });
+                    if self.state.set_block(variable, value).is_none() {
+                        self.emit(&mut result, Element::Error(format!("Cannot assign to constant {}", variable)), stream);
                     }
                 }
-                Content::ElseIf{expression, content} => {
-                    if let Some(false) = if_action {
-                        if_action = Some(expression.eval(&self.state).is_true());
-                        if let Some(true) = if_action {
-                            let content_result = self.eval(content);
-                            result.combine(content_result);
+                Content::Default { variable, expression } => {
+                    let page = self.state.current_page.to_string();
+                    if matches!(self.state.get(&page, variable), None | Some(Value::Null)) {
+                        let value = expression.eval(&self.scope());
+                        self.record(|page| TraceEvent::VariableSet {
+                            page, variable: variable.to_string(), local: false, value: value.clone()
+                        });
+                        if self.state.set_index(variable, &vec![], value).is_none() {
+                            self.emit(&mut result, Element::Error(format!("Cannot assign to constant {}", variable)), stream);
                         }
                     }
                 }
-                Content::Else { content } => {
-                    if let Some(false) = if_action {
-                        if_action = None;
-                        let content_result = self.eval(content);
+                Content::Const { variable, expression } => {
+                    let value = expression.eval(&self.scope());
+                    self.record(|page| TraceEvent::VariableSet {
+                        page, variable: variable.to_string(), local: false, value: value.clone()
+                    });
+                    if let Err(error) = self.state.set_constant(variable, value) {
+                        self.emit(&mut result, Element::Error(error), stream);
+                    }
+                }
+                Content::Conditional { branches, else_branch } => {
+                    let mut taken = false;
+                    for (i, (expression, content)) in branches.iter().enumerate() {
+                        let value = expression.eval(&self.scope());
+                        taken = value.is_true();
+                        let command = if i == 0 { "if" } else { "elseif" };
+                        self.record(|page| TraceEvent::Branch { page, command, taken, value });
+                        if taken {
+                            let content_result = self.eval(content, stream);
+                            result.combine(content_result);
+                            break;
+                        }
+                    }
+                    if let (false, Some(content)) = (taken, else_branch) {
+                        self.record(|page| TraceEvent::Branch { page, command: "else", value: Value::Null, taken: true });
+                        let content_result = self.eval(content, stream);
                         result.combine(content_result);
                     }
                 }
+                Content::ElseIf { .. } | Content::Else { .. } => {
+                    self.emit(&mut result, Element::Error("@else without a matching @if".to_string()), stream);
+                }
                 Content::For { index, variable, expression, content} => {
-                    let iterator_value = expression.eval(&self.state);
-                    for (i, value) in iterator_value.iter() {
+                    let iterator_value = expression.eval(&self.scope());
+                    for (iteration, (i, value)) in iterator_value.iter().into_iter().enumerate() {
+                        if iteration >= self.loop_limit {
+                            self.emit(&mut result, self.loop_limit_error("for"), stream);
+                            break;
+                        }
+                        if self.metrics.step_limit_reached {
+                            break;
+                        }
+                        self.metrics.iterations += 1;
+                        self.record(|page| TraceEvent::LoopIteration { page, command: "for", iteration });
                         if let Some(index) = index {
-                            self.state.set_local(index, i);
+                            self.set_local(&mut result, index, i, stream);
                         }
-                        self.state.set_local(variable, value);
-                        let content_result = self.eval(content);
+                        self.set_local(&mut result, variable, value, stream);
+                        let content_result = self.eval(content, stream);
                         result.combine(content_result);
-                        if let StoryAction::Goto(_) = result.action {
-                            break;
+                        match result.action {
+                            StoryAction::Goto(_) | StoryAction::Return(_) => break,
+                            StoryAction::Break => {
+                                result.action = StoryAction::Halt;
+                                break;
+                            }
+                            StoryAction::Continue => result.action = StoryAction::Halt,
+                            StoryAction::Halt => {}
                         }
                     }
                 }
                 Content::While {expression, content} => {
-                    while expression.eval(&self.state).is_true() {
-                        let content_result = self.eval(content);
-                        result.combine(content_result);
-                        if let StoryAction::Goto(_) = result.action {
+                    let mut iteration = 0;
+                    while expression.eval(&self.scope()).is_true() {
+                        if iteration >= self.loop_limit {
+                            self.emit(&mut result, self.loop_limit_error("while"), stream);
+                            break;
+                        }
+                        if self.metrics.step_limit_reached {
                             break;
                         }
+                        self.metrics.iterations += 1;
+                        self.record(|page| TraceEvent::LoopIteration { page, command: "while", iteration });
+                        iteration += 1;
+                        let content_result = self.eval(content, stream);
+                        result.combine(content_result);
+                        match result.action {
+                            StoryAction::Goto(_) | StoryAction::Return(_) => break,
+                            StoryAction::Break => {
+                                result.action = StoryAction::Halt;
+                                break;
+                            }
+                            StoryAction::Continue => result.action = StoryAction::Halt,
+                            StoryAction::Halt => {}
+                        }
+                    }
+                }
+                Content::Switch { expression, cases, default } => {
+                    let value = expression.eval(&self.scope());
+                    let branch = cases.iter()
+                        .find(|(case, _)| case.eval(&self.scope()) == value)
+                        .map(|(_, content)| content)
+                        .or(default.as_ref());
+                    self.record(|page| TraceEvent::Branch { page, command: "switch", value, taken: branch.is_some() });
+                    if let Some(content) = branch {
+                        let content_result = self.eval(content, stream);
+                        result.combine(content_result);
+                    }
+                }
+                Content::Case { .. } => self.emit(&mut result, Element::Error("@case outside of @switch".to_string()), stream),
+                Content::Random { options } => {
+                    let mut weights = vec![];
+                    for (weight, _) in options.iter() {
+                        let weight = match weight.as_ref().map(|weight| weight.eval(&self.scope())) {
+                            None => 1,
+                            Some(Value::Integer(w)) => w.max(0) as u64,
+                            Some(_) => {
+                                self.emit(&mut result, Element::Error("@option weight must be an integer".to_string()), stream);
+                                0
+                            }
+                        };
+                        weights.push(weight);
+                    }
+                    let total: u64 = weights.iter().sum();
+                    if total > 0 {
+                        let mut choice = self.state.with_rng(|rng| rng.u64(..total));
+                        let index = weights.iter().position(|weight| {
+                            if choice < *weight {
+                                return true;
+                            }
+                            choice -= weight;
+                            false
+                        }).unwrap();
+                        self.record(|page| TraceEvent::Branch {
+                            page, command: "random", value: Value::Integer(index as i64), taken: true
+                        });
+                        let content_result = self.eval(&options[index].1, stream);
+                        result.combine(content_result);
                     }
                 }
-                Content::Error(e) => result.push(Element::Error(e.to_string()))
+                Content::RandomOption { .. } => self.emit(&mut result, Element::Error("@option outside of @random".to_string()), stream),
+                Content::Return(expression) => {
+                    let value = expression.as_ref().map_or(Value::Null, |expression| expression.eval(&self.scope()));
+                    result.action = StoryAction::Return(value);
+                }
+                Content::Label(_) => {}
+                Content::Clear => {
+                    result.output.clear();
+                    result.cleared = true;
+                }
+                Content::Break => result.action = StoryAction::Break,
+                Content::Continue => result.action = StoryAction::Continue,
+                Content::Error(e) => self.emit(&mut result, Element::Error(e.to_string()), stream)
             }
-            if let StoryAction::Goto(_) = result.action {
-                return result;
+            self.emit_errors(&mut result, stream);
+            if let StoryAction::Halt = result.action {
+                continue;
             }
+            return result;
         }
         return result;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(source: &str) -> Interpreter {
+        let story = Story::new(source).unwrap_or_else(|error| panic!("{}", error));
+        let mut interpreter = Interpreter::new(story);
+        interpreter.play();
+        interpreter
+    }
+
+    fn texts(interpreter: &Interpreter) -> Vec<String> {
+        interpreter.output().iter().filter_map(|element| match element {
+            Element::Text(text) => Some(text.to_string()),
+            _ => None
+        }).collect()
+    }
+
+    fn errors(interpreter: &Interpreter) -> Vec<String> {
+        interpreter.output().iter().filter_map(|element| match element {
+            Element::Error(error) => Some(error.to_string()),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn arithmetic_errors_are_shown() {
+        let interpreter = play("# A\n${1 / 0}\n${9223372036854775807 + 1}\n${\"a\" - 1}\n${[1] - 2}\n");
+        assert_eq!(errors(&interpreter), vec![
+            "Division by zero",
+            "Integer overflow in '+'",
+            "Invalid operands for '-': text and integer, use number() to convert text",
+            "Invalid operands for '-': array and integer"
+        ]);
+        assert_eq!(texts(&interpreter), vec!["null", "null", "null", "null"]);
+    }
+
+    #[test]
+    fn math_errors_are_shown() {
+        let interpreter = play("# A\n${abs(\"x\")}\n${sqrt(-1)}\n${floor(2.5)}\n");
+        assert_eq!(errors(&interpreter), vec!["abs() expects numbers", "sqrt() of a negative number"]);
+        assert_eq!(texts(&interpreter), vec!["null", "null", "2"]);
+    }
+
+    #[test]
+    fn actions_run_from_compiled_stories_and_loaded_states() {
+        let source = "# A\n@link Inc { Inc }\n@input x { You typed ${x} }\n";
+        let compiled = Story::new(source).ok().and_then(|story| story.compile().ok()).unwrap();
+        let mut interpreter = Interpreter::new(Story::from_compiled(&compiled).ok().unwrap());
+        interpreter.play();
+        interpreter.send(0, Value::Null).unwrap();
+        assert_eq!(texts(&interpreter), vec!["Inc"]);
+
+        let saved = play(source).dump_state().unwrap();
+        let mut interpreter = Interpreter::new(Story::new(source).ok().unwrap());
+        interpreter.load_state(&saved).ok().unwrap();
+        interpreter.send(1, Value::Text("hi".to_string())).unwrap();
+        interpreter.send(0, Value::Null).unwrap();
+        assert_eq!(texts(&interpreter), vec!["Inc", "You typed hi"]);
+    }
+
+    #[test]
+    fn formatted_conditions_match_their_compact_forms() {
+        let formatted = "@if a   and\tb {\none\n}\n@if (a and\n  not b) or b {\ntwo\n}\n@if a and @@ note\n b {\nthree\n}\n";
+        let compact = "@if a and b {\none\n}\n@if (a and not b) or b {\ntwo\n}\n@if a and b {\nthree\n}\n";
+        for (a, b) in [(true, true), (true, false), (false, true), (false, false)] {
+            let variables = format!("# A\n@set a = {}\n@set b = {}\n", a, b);
+            let formatted = play(&format!("{}{}", variables, formatted));
+            assert!(errors(&formatted).is_empty());
+            assert_eq!(texts(&formatted), texts(&play(&format!("{}{}", variables, compact))), "a = {}, b = {}", a, b);
+        }
+    }
+
+    #[test]
+    fn streamed_elements_match_the_output() {
+        let story = Story::new("# A\nBefore\n@set x = 1 / 0\nValue ${1 / 0} and ${sqrt(-1)}\n@link Next -> B\n").ok().unwrap();
+        let mut interpreter = Interpreter::new(story);
+        let mut streamed = vec![];
+        interpreter.play_streaming(|element| streamed.push(element));
+        let json = |elements: &[Element]| serde_json::to_string(elements).unwrap();
+        assert_eq!(json(&streamed), json(interpreter.output()));
+        assert_eq!(errors(&interpreter).len(), 3);
+    }
+
+    #[test]
+    fn empty_rand_ranges_are_errors() {
+        let interpreter = play("# A\n${rand(0)}\n${rand(3, 3)}\n");
+        assert_eq!(errors(&interpreter), vec!["rand(0) needs a positive limit", "rand(3, 3) is an empty range"]);
+        assert_eq!(texts(&interpreter), vec!["null", "null"]);
+    }
+
+    #[test]
+    fn interpolated_text_shows_values_and_invalid_expressions() {
+        let interpreter = play("# A\n@set gold = 5\n@set bag = [1, 2.5, \"x\"]\nYou have ${gold} coins, ${gold / 2} each.\nBag ${bag}\nA ${gold gold} b\nBraces {gold} and {{gold}} stay.\n");
+        assert_eq!(texts(&interpreter), vec![
+            "You have 5 coins, 2.5 each.",
+            "Bag [1, 2.5, x]",
+            "A [invalid expression: gold gold] b",
+            "Braces {gold} and {{gold}} stay."
+        ]);
+        assert!(errors(&interpreter).is_empty());
+    }
+
+    #[test]
+    fn escaped_syntax_is_kept_as_text() {
+        let interpreter = play("# A\nSee \\[not a link\\] here\nArrow \\-> there\nParens \\(x\\) and \\*stars\\*\nCost \\$5 and \\${x}\nBack\\\\slash\n\\@set x = 1\n");
+        assert_eq!(texts(&interpreter), vec![
+            "See [not a link] here",
+            "Arrow -> there",
+            "Parens (x) and *stars*",
+            "Cost $5 and ${x}",
+            "Back\\slash",
+            "@set x = 1"
+        ]);
+        assert!(interpreter.choices().is_empty());
+    }
+
+    #[test]
+    fn comments_are_stripped_outside_strings() {
+        assert_eq!(Story::strip_comment("Text @@ note"), ("Text", true));
+        assert_eq!(Story::strip_comment("Text \\@@ kept"), ("Text \\@@ kept", false));
+        assert_eq!(Story::strip_comment("@set x = \"a@@b\" @@ note"), ("@set x = \"a@@b\"", true));
+        assert_eq!(Story::strip_comment("Hi ${\"@@\"} @@ note"), ("Hi ${\"@@\"}", true));
+        // Quotes in text aren't strings, and a quote without a closing one doesn't hide a comment.
+        assert_eq!(Story::strip_comment("She said \"hi @@ note\""), ("She said \"hi", true));
+        assert_eq!(Story::strip_comment("@set x = 1 @@ the \" quote"), ("@set x = 1", true));
+        assert_eq!(Story::strip_comment("// a note"), ("", true));
+    }
+
+    #[test]
+    fn at_signs_in_string_literals_are_kept() {
+        let interpreter = play("# A\n@set email = \"me@@example.com\" @@ not shown\n${email}\n${\"a@@b\"} @@ hidden\n");
+        assert_eq!(texts(&interpreter), vec!["me@@example.com", "a@@b"]);
+    }
+
+    #[test]
+    fn goto_from_nested_for() {
+        let interpreter = play("# A\n@for i in [1, 2] {\n@for j in [1, 2] {\n@goto B\n}\nInner done\n}\nAfter loops\n# B\nAt B\n");
+        assert_eq!(interpreter.state.current_page, "B");
+        assert_eq!(texts(&interpreter), vec!["At B"]);
+    }
+
+    #[test]
+    fn goto_from_while() {
+        let interpreter = play("# A\n@set n = 0\n@while n < 10 {\n@set n = n + 1\n@if n == 3 {\n@goto B\n}\n}\nAfter loop\n# B\nAt B with ${n}\n");
+        assert_eq!(interpreter.state.current_page, "B");
+        assert_eq!(texts(&interpreter), vec!["At B with 3"]);
+    }
+
+    #[test]
+    fn goto_from_imported_page() {
+        let interpreter = play("# A\nBefore\n@import Shared\nAfter import\n# Shared\n@goto B\nAfter goto\n# B\nAt B\n");
+        assert_eq!(interpreter.state.current_page, "B");
+        assert_eq!(texts(&interpreter), vec!["At B"]);
+    }
+
+    #[test]
+    fn goto_from_if_in_for() {
+        let interpreter = play("# A\n@for i in [1, 2, 3] {\n@if i == 2 {\n@goto B\n}\nItem ${i}\n}\nAfter loop\n# B\nAt B\n");
+        assert_eq!(interpreter.state.current_page, "B");
+        assert_eq!(texts(&interpreter), vec!["At B"]);
+    }
+
+    #[test]
+    fn goto_from_loop_in_link_action() {
+        let mut interpreter = play("# A\n@link Go {\n@for i in [1, 2] {\n@goto B\n}\nAfter loop\n}\n# B\nAt B\n");
+        interpreter.send(0, Value::Null).unwrap();
+        assert_eq!(interpreter.state.current_page, "B");
+        assert_eq!(texts(&interpreter), vec!["At B"]);
+    }
+}
@@ -3,21 +3,30 @@ use std::ops;
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use fastrand;
+use regex::Regex;
+use lazy_static::lazy_static;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content="value")]
 pub enum Value {
     Integer(i64),
     Float(f64),
     Text(String),
     Array(Vec<Value>),
-    Object(HashMap<String, Value>),
+    Object(#[serde(serialize_with = "ordered::map")] HashMap<String, Value>),
     Boolean(bool),
     Null
 }
 
 use Value::*;
 
+// Object entries ordered by key, so output and iteration don't depend on hashing.
+fn sorted(obj: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+    let mut entries: Vec<_> = obj.iter().collect();
+    entries.sort_by_key(|(key, _)| *key);
+    entries
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let r = match self {
@@ -32,7 +41,7 @@ impl fmt::Display for Value {
                     .join(", "))
         },
         Object(obj) => {
-            format!("{{{}}}", obj.iter()
+            format!("{{{}}}", sorted(obj).into_iter()
                     .map(|(k, v)| format!("{}: {}", k.to_string(), v.to_string()))
                     .collect::<Vec<String>>()
                     .join(", "))
@@ -43,6 +52,78 @@ impl fmt::Display for Value {
     }
 }
 
+// Controls how values are written to the story output.
+#[derive(Clone)]
+pub struct ValueFormat {
+    // Separator between groups of three integer digits, e.g. Some(',') for 1,000,000.
+    pub group_separator: Option<char>,
+    // Fixed number of decimals for floats.
+    pub decimals: Option<usize>,
+    pub true_text: String,
+    pub false_text: String
+}
+
+impl Default for ValueFormat {
+    fn default() -> Self {
+        ValueFormat {
+            group_separator: None,
+            decimals: None,
+            true_text: "true".to_string(),
+            false_text: "false".to_string()
+        }
+    }
+}
+
+impl ValueFormat {
+    fn group(&self, number: String) -> String {
+        let separator = match self.group_separator {
+            Some(separator) => separator,
+            None => return number
+        };
+        let (sign, digits) = match number.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", number.as_str())
+        };
+        let (integer, fraction) = match digits.find(|c: char| !c.is_ascii_digit()) {
+            Some(index) => digits.split_at(index),
+            None => (digits, "")
+        };
+        let mut grouped = String::new();
+        for (i, c) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                grouped.push(separator);
+            }
+            grouped.push(c);
+        }
+        format!("{}{}{}", sign, grouped, fraction)
+    }
+
+    pub fn format(&self, value: &Value) -> String {
+        match value {
+            Integer(a) => self.group(a.to_string()),
+            Float(a) => match self.decimals {
+                Some(decimals) if a.is_finite() => self.group(format!("{:.*}", decimals, a)),
+                _ if a.is_finite() => self.group(a.to_string()),
+                _ => a.to_string()
+            },
+            Boolean(b) => if *b { self.true_text.to_string() } else { self.false_text.to_string() },
+            Array(items) => {
+                format!("[{}]", items.iter()
+                        .map(|item| self.format(item))
+                        .collect::<Vec<String>>()
+                        .join(", "))
+            },
+            Object(obj) => {
+                format!("{{{}}}", sorted(obj).into_iter()
+                        .map(|(k, v)| format!("{}: {}", k, self.format(v)))
+                        .collect::<Vec<String>>()
+                        .join(", "))
+            },
+            _ => value.to_string()
+        }
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -56,12 +137,28 @@ impl PartialEq for Value {
             (Array(a), Array(b)) => {
                 (a.len() == b.len()) && a.iter().zip(b).all(|(i, j)| { i == j })
             },
+            (Object(a), Object(b)) => {
+                (a.len() == b.len()) && a.iter().all(|(key, i)| b.get(key) == Some(i))
+            },
             _ => false
         }
     }
 }   
 
 impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Integer(_) => "integer",
+            Float(_) => "float",
+            Text(_) => "text",
+            Array(_) => "array",
+            Object(_) => "object",
+            Boolean(_) => "boolean",
+            Null => "null"
+        }
+    }
+
+    // Null, 0, 0.0, false, "" and empty arrays and objects are false, every other value is true.
     pub fn is_true(&self) -> bool {
         match self {
             Integer(a) => { *a != 0 },
@@ -69,6 +166,15 @@ impl Value {
             Text(a) => { *a != "" },
             Boolean(a) => { *a },
             Array(a) => { a.len() > 0 }
+            Object(a) => { !a.is_empty() }
+            _ => false
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Integer(a) => *a == 0,
+            Float(a) => *a == 0.0,
             _ => false
         }
     }
@@ -93,9 +199,19 @@ impl Value {
     pub fn get_mut(&mut self, references: &Vec<Value>) -> Option<&mut Value> {
         let mut reference: &mut Value = self;
 
-        for index in references.iter() {
+        for (position, index) in references.iter().enumerate() {
+            // Indexing into null creates the container.
+            match (&*reference, index) {
+                (Null, Integer(0)) => *reference = Array(vec![]),
+                (Null, Text(_)) => *reference = Object(HashMap::new()),
+                _ => {}
+            }
             match (&mut *reference, index) {
-                (Array(arr), Integer(i)) => {
+                (Array(arr), Integer(i)) if *i >= 0 => {
+                    // Assigning one past the end of an array appends to it.
+                    if *i as usize == arr.len() && position == references.len() - 1 {
+                        arr.push(Null);
+                    }
                     reference = arr.get_mut(*i as usize)?;
                 }
                 (Object(obj), Text(key)) => {
@@ -113,11 +229,26 @@ impl Value {
         return Some(reference);
     }
 
+    // Plain JSON, as passed in by hosts: numbers without a fraction become integers.
+    pub fn from_json(json: serde_json::Value) -> Value {
+        match json {
+            serde_json::Value::Null => Null,
+            serde_json::Value::Bool(b) => Boolean(b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Integer(i),
+                None => n.as_f64().map_or(Null, Float)
+            },
+            serde_json::Value::String(s) => Text(s),
+            serde_json::Value::Array(arr) => Array(arr.into_iter().map(Value::from_json).collect()),
+            serde_json::Value::Object(obj) => Object(obj.into_iter().map(|(key, value)| (key, Value::from_json(value))).collect())
+        }
+    }
+
     pub fn iter(&self) -> Vec<(Value, Value)> {
         match self {
             Array(arr) => arr.iter().enumerate().map(|(index, value)| (Integer(index as i64), value.clone())).collect(),
             Text(s) => s.chars().enumerate().map(|(index, value)| (Integer(index as i64), Text(value.to_string()))).collect(),
-            Object(o) => o.iter().map(|(key, value)| (Value::Text(key.to_string()), value.clone())).collect(),
+            Object(o) => sorted(o).into_iter().map(|(key, value)| (Value::Text(key.to_string()), value.clone())).collect(),
             _ => vec![]
         }
     }
@@ -129,7 +260,7 @@ impl ops::Neg for Value {
 
     fn neg(self) -> Value {
         match self {
-            Integer(a) => Integer(-a),
+            Integer(a) => a.checked_neg().map_or(Null, Integer),
             Float(a) => Float(-a),
             _ => Null
         }
@@ -141,7 +272,7 @@ impl ops::Add for Value {
 
     fn add(self, other: Value) -> Value {
         match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a + b),
+            (Integer(a), Integer(b)) => a.checked_add(b).map_or(Null, Integer),
             (Float(a),   Integer(b)) => Float(a + b as f64),
             (Integer(a), Float(b))   => Float(a as f64 + b),
             (Float(a),   Float(b))   => Float(a + b),
@@ -165,7 +296,7 @@ impl ops::Mul for Value {
 
     fn mul(self, other: Value) -> Value {
         match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a * b),
+            (Integer(a), Integer(b)) => a.checked_mul(b).map_or(Null, Integer),
             (Integer(a), Float(b))   => Float(a as f64 * b),
             (Float(a), Integer(b))   => Float(a * b as f64),
             (Float(a), Float(b))     => Float(a * b),
@@ -183,8 +314,8 @@ impl ops::Mul for Value {
                 }
                 Array(ret)
             }
-            (Text(a), Integer(b))    => Text(a.repeat(b as usize)),
-            (Integer(a), Text(b))    => Text(b.repeat(a as usize)),
+            (Text(a), Integer(b)) if b >= 0 => Text(a.repeat(b as usize)),
+            (Integer(a), Text(b)) if a >= 0 => Text(b.repeat(a as usize)),
             _ => Null
         }
     }
@@ -194,7 +325,11 @@ impl ops::Mul for Value {
 impl ops::Div for Value {
     type Output = Value;
 
+    // Division by zero results in null.
     fn div(self, other: Value) -> Value {
+        if other.is_zero() {
+            return Null;
+        }
         match (self, other) {
             (Integer(a), Integer(b)) => Float(a as f64 / b as f64),
             (Integer(a), Float(b))   => Float(a as f64 / b),
@@ -209,8 +344,11 @@ impl ops::Rem for Value {
     type Output = Value;
     
     fn rem(self, other: Value) -> Value {
+        if other.is_zero() {
+            return Null;
+        }
         match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a % b),
+            (Integer(a), Integer(b)) => a.checked_rem(b).map_or(Null, Integer),
             (Float(a), Integer(b)) => Float(a % b as f64),
             (Integer(a), Float(b)) => Float(a as f64 % b),
             (Float(a), Float(b)) => Float(a % b),
@@ -224,7 +362,7 @@ impl ops::Sub for Value {
 
     fn sub(self, other: Value) -> Value {
         match (self, other) {
-            (Integer(a), Integer(b)) => Integer(a - b),
+            (Integer(a), Integer(b)) => a.checked_sub(b).map_or(Null, Integer),
             (Integer(a), Float(b))   => Float(a as f64 - b),
             (Float(a), Integer(b))   => Float(a - b as f64),
             (Float(a), Float(b))     => Float(a - b),
@@ -234,6 +372,30 @@ impl ops::Sub for Value {
 }
 
 
+// Serializes maps and sets sorted, so the same state always gives the same save.
+// Only the serialized form is ordered, values keep their hash maps.
+pub mod ordered {
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+    use serde::{Serialize, Serializer};
+
+    pub fn map<K: Ord + Serialize, V: Serialize, S: Serializer>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> {
+        map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+    }
+
+    // Maps of maps, such as the local variables of each page.
+    pub fn nested_map<V: Serialize, S: Serializer>(map: &HashMap<String, HashMap<String, V>>, serializer: S) -> Result<S::Ok, S::Error> {
+        map.iter()
+            .map(|(key, inner)| (key, inner.iter().collect::<BTreeMap<_, _>>()))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn set<T: Ord + Serialize, S: Serializer>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        set.iter().collect::<BTreeSet<_>>().serialize(serializer)
+    }
+}
+
+
 pub mod comparison {
     use crate::value::{Value, Value::*};
     fn numeric_comparison<F>(lhs: &Value, rhs: &Value, lambda: F) -> Value where F: Fn(f64, f64) -> bool {
@@ -288,8 +450,207 @@ pub mod operator {
 }
 
 impl Value {
-    pub fn eval_function(name: &str, values: Vec<Value>) -> Value {
-        match name {
+    // Functions that draw from a random number generator.
+    pub fn eval_random(name: &str, values: &[Value], rng: &fastrand::Rng) -> Option<Result<Value, String>> {
+        let value = match name {
+            "pickrandom" | "pick" => {
+                match values.first() {
+                    Some(Array(vec)) => {
+                        if !vec.is_empty() {
+                            let i = rng.usize(..vec.len());
+                            vec[i].clone()
+                        }
+                        else { Null }
+                    }
+                    _ => Null
+                }
+            }
+            "probability" => {
+                match values.first() {
+                    Some(Float(p)) => { Boolean(rng.f64() < *p) },
+                    Some(Integer(p)) => { Boolean(rng.f64() < *p as f64) },
+                    _ => Null
+                }
+            }
+            "rand" => {
+                match (values.first(), values.get(1)) {
+                    (Some(Integer(a)), None) if *a <= 0 => return Some(Err(format!("rand({}) needs a positive limit", a))),
+                    (Some(Integer(a)), Some(Integer(b))) if a >= b => return Some(Err(format!("rand({}, {}) is an empty range", a, b))),
+                    (None, None) => Float(rng.f64()),
+                    (Some(Integer(a)), None) => Integer(rng.i64(0..*a)),
+                    (Some(Float(a)), None) => Float(rng.f64() * a),
+                    (Some(Integer(a)), Some(Integer(b))) => Integer(rng.i64(a..b)),
+                    (Some(Float(a)), Some(Float(b))) => Float(a + rng.f64() * (b - a)),
+                    (Some(Integer(a)), Some(Float(b))) => Float(*a as f64 + rng.f64() * (b - *a as f64)),
+                    (Some(Float(a)), Some(Integer(b))) => Float(a + rng.f64() * (*b as f64 - a)),
+                    _ => Null
+                }
+            }
+            // Picks an item with a probability proportional to its weight.
+            "pick_weighted" => {
+                let (items, weights) = match (values.first(), values.get(1)) {
+                    (Some(Array(items)), Some(Array(weights))) if items.len() == weights.len() => (items, weights),
+                    _ => return Some(Err("pick_weighted() expects a list of items and a list of weights of the same length".to_string()))
+                };
+                let weights: Option<Vec<f64>> = weights.iter()
+                    .map(|weight| weight.as_f64().filter(|weight| *weight >= 0.0))
+                    .collect();
+                let weights = match weights {
+                    Some(weights) if weights.iter().sum::<f64>() > 0.0 => weights,
+                    _ => return Some(Err("pick_weighted() expects weights of at least 0 with a positive total".to_string()))
+                };
+                let mut choice = rng.f64() * weights.iter().sum::<f64>();
+                let index = weights.iter().position(|weight| {
+                    if choice < *weight {
+                        return true;
+                    }
+                    choice -= weight;
+                    false
+                });
+                // Rounding can leave the choice past the last weight, which then gets picked.
+                let index = index.unwrap_or_else(|| weights.iter().rposition(|weight| *weight > 0.0).unwrap_or(0));
+                items[index].clone()
+            }
+            _ => return None
+        };
+        Some(Ok(value))
+    }
+
+    // Dice notation: NdS or dS, optionally followed by +K or -K.
+    fn parse_dice(notation: &str) -> Option<(i64, i64, i64)> {
+        lazy_static! {
+            static ref DICE_REGEX: Regex = Regex::new(r"^\s*(\d*)[dD](\d+)\s*(?:([+-])\s*(\d+))?\s*$").unwrap();
+        }
+        let captures = DICE_REGEX.captures(notation)?;
+        let count = match captures.get(1).map_or("", |m| m.as_str()) {
+            "" => 1,
+            count => count.parse().ok()?
+        };
+        let sides = captures.get(2)?.as_str().parse().ok()?;
+        let modifier: i64 = captures.get(4).map_or(Some(0), |m| m.as_str().parse().ok())?;
+        let modifier = if captures.get(3).map_or("+", |m| m.as_str()) == "-" { -modifier } else { modifier };
+        Some((count, sides, modifier))
+    }
+
+    // roll("2d6+1") or roll(count, sides) gives the total, roll_all gives each die without the modifier.
+    pub fn eval_dice(name: &str, values: &[Value], rng: &fastrand::Rng) -> Option<Result<Value, String>> {
+        let dice = match values {
+            [Text(notation)] => Self::parse_dice(notation),
+            [Integer(count), Integer(sides)] => Some((*count, *sides, 0)),
+            _ => None
+        };
+        let (count, sides, modifier) = match (name, dice) {
+            ("roll" | "roll_all", Some((count, sides, modifier))) if (1..=1000).contains(&count) && sides >= 1 => {
+                (count, sides, modifier)
+            }
+            ("roll" | "roll_all", _) => {
+                let arguments: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                return Some(Err(format!("Invalid dice for {}(): {}", name, arguments.join(", "))));
+            }
+            _ => return None
+        };
+        let rolls: Vec<i64> = (0..count).map(|_| rng.i64(1..=sides)).collect();
+        let result = match name {
+            "roll_all" => Ok(Array(rolls.into_iter().map(Integer).collect())),
+            _ => rolls.iter().try_fold(modifier, |total, roll| total.checked_add(*roll))
+                .map(Integer)
+                .ok_or("Integer overflow in roll()".to_string())
+        };
+        Some(result)
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Integer(i) => Some(*i as f64),
+            Float(f) => Some(*f),
+            _ => None
+        }
+    }
+
+    // Math functions, which fail with a message on arguments that aren't numbers.
+    // Integers stay integers where possible, rounding a float gives an integer.
+    pub fn eval_math(name: &str, values: &[Value]) -> Option<Result<Value, String>> {
+        if !matches!(name, "min" | "max" | "clamp" | "abs" | "floor" | "ceil" | "round" | "sqrt" | "pow") {
+            return None;
+        }
+        let numbers: Option<Vec<f64>> = values.iter().map(Value::as_f64).collect();
+        let numbers = match numbers {
+            Some(numbers) => numbers,
+            None => return Some(Err(format!("{}() expects numbers", name)))
+        };
+        let result = match (name, values, numbers.as_slice()) {
+            ("min" | "max", [], _) => Err(format!("{}() expects at least one number", name)),
+            ("min" | "max", _, _) => {
+                let mut best = 0;
+                for (i, number) in numbers.iter().enumerate() {
+                    if (name == "min" && *number < numbers[best]) || (name == "max" && *number > numbers[best]) {
+                        best = i;
+                    }
+                }
+                Ok(values[best].clone())
+            }
+            ("clamp", [value, low, high], [x, min, max]) => match (x, min, max) {
+                _ if min > max => Err("clamp() expects the minimum to be at most the maximum".to_string()),
+                (x, min, _) if x < min => Ok(low.clone()),
+                (x, _, max) if x > max => Ok(high.clone()),
+                _ => Ok(value.clone())
+            },
+            ("abs", [Integer(i)], _) => i.checked_abs().map(Integer).ok_or("Integer overflow in abs()".to_string()),
+            ("abs", [Float(f)], _) => Ok(Float(f.abs())),
+            ("floor" | "ceil" | "round", [Integer(i)], _) => Ok(Integer(*i)),
+            ("floor", [Float(f)], _) => Ok(Integer(f.floor() as i64)),
+            ("ceil", [Float(f)], _) => Ok(Integer(f.ceil() as i64)),
+            // Halves round away from zero. With a number of digits the result stays a float.
+            ("round", [Float(f)], _) => Ok(Integer(f.round() as i64)),
+            ("round", [_, Integer(digits)], [f, _]) => {
+                let scale = 10f64.powi((*digits).clamp(-308, 308) as i32);
+                Ok(Float((f * scale).round() / scale))
+            }
+            ("sqrt", _, [x]) if *x < 0.0 => Err("sqrt() of a negative number".to_string()),
+            ("sqrt", _, [x]) => Ok(Float(x.sqrt())),
+            ("pow", [Integer(a), Integer(b)], _) if *b >= 0 => {
+                u32::try_from(*b).ok()
+                    .and_then(|b| a.checked_pow(b))
+                    .map(Integer)
+                    .ok_or("Integer overflow in pow()".to_string())
+            }
+            ("pow", [a, b], _) => Ok(a.pow(b)),
+            _ => Err(format!("Wrong number of arguments for {}()", name))
+        };
+        Some(result)
+    }
+
+    // Explicit conversions, since arithmetic never converts text: "2" + 1 is an error.
+    // Surrounding whitespace is ignored, text that isn't a number or a boolean fails with a message.
+    pub fn eval_conversion(name: &str, values: &[Value]) -> Option<Result<Value, String>> {
+        let value = match (name, values) {
+            ("number", [Text(s)]) => {
+                let s = s.trim();
+                match s.parse::<i64>() {
+                    Ok(i) => Ok(Integer(i)),
+                    Err(_) => s.parse::<f64>().ok().filter(|f| f.is_finite()).map(Float)
+                        .ok_or(format!("number() can't convert '{}'", s))
+                }
+            }
+            ("number", [Integer(i)]) => Ok(Integer(*i)),
+            ("number", [Float(f)]) => Ok(Float(*f)),
+            ("number", [Boolean(b)]) => Ok(Integer(*b as i64)),
+            ("number", [value]) => Err(format!("number() can't convert {}", value.type_name())),
+            ("bool", [Text(s)]) => match s.trim().to_lowercase().as_str() {
+                "true" | "yes" | "1" => Ok(Boolean(true)),
+                "false" | "no" | "0" | "" => Ok(Boolean(false)),
+                _ => Err(format!("bool() can't convert '{}'", s.trim()))
+            },
+            ("bool", [value]) => Ok(Boolean(value.is_true())),
+            ("number" | "bool", _) => Err(format!("Wrong number of arguments for {}()", name)),
+            _ => return None
+        };
+        Some(value)
+    }
+
+    // Returns None if there is no built-in function with this name.
+    pub fn eval_function(name: &str, values: Vec<Value>) -> Option<Value> {
+        let value = match name {
             "int" => {
                 match values.get(0) {
                     Some(Text(s)) => s.parse::<i64>().map_or(Null, |i| Integer(i)),
@@ -319,90 +680,116 @@ impl Value {
                     _ => Null
                 }
             }
-            "len" => {
-                match values.get(0) {
-                    Some(Array(a)) => Integer(a.len() as i64),
-                    Some(Object(o)) => Integer(o.len() as i64),
-                    Some(Text(s)) => Integer(s.len() as i64),
+            "trim" => {
+                match values.first() {
+                    Some(Text(s)) => Text(s.trim().to_string()),
                     _ => Null
                 }
             }
-            "keys" => {
-                match values.get(0) {
-                    Some(Object(o)) => Array(o.keys().map(|k| Text(k.to_string())).collect()),
+            // Characters from start up to end, or to the end of the text. Indices count characters, not bytes.
+            "substr" => {
+                let length = match values.first() {
+                    Some(Text(s)) => s.chars().count() as i64,
+                    _ => 0
+                };
+                match (values.first(), values.get(1), values.get(2)) {
+                    (Some(Text(s)), Some(Integer(start)), end @ (None | Some(Integer(_)))) => {
+                        let start = (*start).clamp(0, length) as usize;
+                        let end = match end {
+                            Some(Integer(end)) => (*end).clamp(0, length) as usize,
+                            _ => length as usize
+                        };
+                        Text(s.chars().skip(start).take(end.saturating_sub(start)).collect())
+                    }
                     _ => Null
                 }
             }
-            "pickrandom" => {
-                match values.get(0) {
-                    Some(Array(vec)) => {
-                        if vec.len() > 0 {
-                            let i = fastrand::usize(..vec.len());
-                            vec[i].clone()
-                        }
-                        else { Null }
-                    }
+            "replace" => {
+                match (values.first(), values.get(1), values.get(2)) {
+                    (Some(Text(s)), Some(Text(from)), Some(Text(to))) if !from.is_empty() => Text(s.replace(from, to)),
                     _ => Null
                 }
             }
-            "probability" => {
-                match values.get(0) {
-                    Some(Float(p)) => { Boolean(fastrand::f64() < *p) },
-                    Some(Integer(p)) => { Boolean(fastrand::f64() < *p as f64) },
+            "contains" => {
+                match (values.first(), values.get(1)) {
+                    (Some(Text(s)), Some(Text(sub))) => Boolean(s.contains(sub.as_str())),
                     _ => Null
                 }
             }
-            "rand" => {
-                match (values.get(0), values.get(1)) {
-                    (None, None) => Float(fastrand::f64()),
-                    (Some(Integer(a)), None) => Integer(fastrand::i64(0..*a)),
-                    (Some(Float(a)), None) => Float(fastrand::f64() * a),
-                    (Some(Integer(a)), Some(Integer(b))) => Integer(fastrand::i64(a..b)),
-                    (Some(Float(a)), Some(Float(b))) => Float(a + fastrand::f64() * (b - a)),
-                    (Some(Integer(a)), Some(Float(b))) => Float(*a as f64 + fastrand::f64() * (b - *a as f64)),
-                    (Some(Float(a)), Some(Integer(b))) => Float(a + fastrand::f64() * (*b as f64 - a)),
+            "len" | "length" => {
+                match values.get(0) {
+                    Some(Array(a)) => Integer(a.len() as i64),
+                    Some(Object(o)) => Integer(o.len() as i64),
+                    Some(Text(s)) => Integer(s.chars().count() as i64),
                     _ => Null
                 }
             }
-            "range" => {
-                match (values.get(0), values.get(1)) {
-                    (Some(Integer(start)), None) => {
-                        Array((0..*start).map(|x| -> Value {Integer(x)}).collect())
-                    }
-                    (Some(Integer(start)), Some(Integer(end))) => {
-                        Array((*start..*end).map(|x| -> Value {Integer(x)}).collect())
+            // Values can't be modified in place, push and pop return a new array. The value pop removes
+            // is read with last() first: @set top = last(stack) then @set stack = pop(stack).
+            "push" => {
+                match (values.first(), values.get(1)) {
+                    (Some(Array(a)), Some(value)) => {
+                        let mut a = a.clone();
+                        a.push(value.clone());
+                        Array(a)
                     }
                     _ => Null
                 }
             }
-            "sqrt" => {
-                match values.get(0) {
-                    Some(Integer(k)) => Float((*k as f64).sqrt()),
-                    Some(Float(k)) => Float(k.sqrt()),
+            "pop" => {
+                match values.first() {
+                    Some(Array(a)) => Array(a[..a.len().saturating_sub(1)].to_vec()),
                     _ => Null
                 }
             }
-            "floor" => {
-                match values.get(0) {
-                    Some(Integer(k)) => Integer(*k),
-                    Some(Float(k)) => Integer(k.floor() as i64),
+            "first" | "last" => {
+                match values.first() {
+                    Some(Array(a)) => match name {
+                        "first" => a.first(),
+                        _ => a.last()
+                    }.cloned().unwrap_or(Null),
                     _ => Null
                 }
             }
-            "ceil" => {
+            "keys" => {
                 match values.get(0) {
-                    Some(Integer(k)) => Integer(*k),
-                    Some(Float(k)) => Integer(k.ceil() as i64),
+                    Some(Object(o)) => Array(sorted(o).into_iter().map(|(k, _)| Text(k.to_string())).collect()),
                     _ => Null
                 }
             }
-            "abs" => {
-                match values.get(0) {
-                    Some(Integer(k)) => Integer(k.abs()),
-                    Some(Float(k)) => Float(k.abs()),
+            "values" => {
+                match values.first() {
+                    Some(Object(o)) => Array(sorted(o).into_iter().map(|(_, v)| v.clone()).collect()),
+                    _ => Null
+                }
+            }
+            "has" => {
+                match (values.first(), values.get(1)) {
+                    (Some(Object(o)), Some(Text(key))) => Boolean(o.contains_key(key)),
                     _ => Null
                 }
             }
+            "pickrandom" | "pick" | "pick_weighted" | "probability" | "rand" => {
+                return Self::eval_random(name, &values, &fastrand::Rng::new()).map(|result| result.unwrap_or(Null));
+            }
+            "roll" | "roll_all" => return Self::eval_dice(name, &values, &fastrand::Rng::new()).map(|result| result.unwrap_or(Null)),
+            "range" => {
+                match (values.get(0), values.get(1)) {
+                    (Some(Integer(start)), None) => {
+                        Array((0..*start).map(|x| -> Value {Integer(x)}).collect())
+                    }
+                    (Some(Integer(start)), Some(Integer(end))) => {
+                        Array((*start..*end).map(|x| -> Value {Integer(x)}).collect())
+                    }
+                    _ => Null
+                }
+            }
+            "min" | "max" | "clamp" | "abs" | "floor" | "ceil" | "round" | "sqrt" | "pow" => {
+                return Self::eval_math(name, &values).map(|result| result.unwrap_or(Null));
+            }
+            "number" | "bool" => {
+                return Self::eval_conversion(name, &values).map(|result| result.unwrap_or(Null));
+            }
             "if" => {
                 let mut return_value = Null;
                 let mut iterator = values.iter();
@@ -418,7 +805,184 @@ impl Value {
                 }
                 return_value
             }
-            _ => Null
+            // plural(count, singular, plural) or plural(count, singular, plural, zero) picks the word for the count.
+            "plural" => {
+                let count = values.first().and_then(Value::as_f64);
+                match (count, &values[values.len().min(1)..]) {
+                    (Some(0.0), [_, _, zero]) => zero.clone(),
+                    (Some(count), [singular, _, ..]) if count.abs() == 1.0 => singular.clone(),
+                    (Some(_), [_, plural] | [_, plural, _]) => plural.clone(),
+                    _ => Null
+                }
+            }
+            // Same as the ?? operator: the fallback is used when the value is null.
+            "default" => {
+                match (values.first(), values.get(1)) {
+                    (Some(value), Some(fallback)) => value.coalesce(fallback),
+                    (Some(value), None) => value.clone(),
+                    _ => Null
+                }
+            }
+            _ => return None
+        };
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Text(s.to_string())
+    }
+
+    #[test]
+    fn mixed_integer_and_float_arithmetic() {
+        assert_eq!(Integer(2) + Float(0.5), Float(2.5));
+        assert_eq!(Float(1.5) * Integer(2), Float(3.0));
+        assert_eq!(Integer(7) - Float(0.5), Float(6.5));
+        assert_eq!(Integer(7) / Integer(2), Float(3.5));
+        assert_eq!(Integer(7) % Integer(4), Integer(3));
+    }
+
+    #[test]
+    fn text_operators() {
+        assert_eq!(text("ab") + text("cd"), text("abcd"));
+        assert_eq!(text("ab") * Integer(2), text("abab"));
+        assert_eq!(text("ab") * Integer(-1), Null);
+    }
+
+    #[test]
+    fn division_by_zero_is_null() {
+        assert_eq!(Integer(1) / Integer(0), Null);
+        assert_eq!(Float(1.0) / Float(0.0), Null);
+        assert_eq!(Integer(1) % Integer(0), Null);
+    }
+
+    #[test]
+    fn integer_overflow_is_null() {
+        assert_eq!(Integer(i64::MAX) + Integer(1), Null);
+        assert_eq!(Integer(i64::MIN) - Integer(1), Null);
+        assert_eq!(Integer(i64::MAX) * Integer(2), Null);
+        assert_eq!(-Integer(i64::MIN), Null);
+        assert_eq!(Integer(i64::MIN) % Integer(-1), Null);
+    }
+
+    #[test]
+    fn non_numeric_operands_are_null() {
+        assert_eq!(text("a") - Integer(1), Null);
+        assert_eq!(text("a") + Integer(1), Null);
+        assert_eq!(Boolean(true) + Integer(1), Null);
+        assert_eq!(Array(vec![Integer(1)]) / Integer(2), Null);
+    }
+
+    fn math(name: &str, values: &[Value]) -> Result<Value, String> {
+        Value::eval_math(name, values).expect("a math function")
+    }
+
+    #[test]
+    fn min_max_keep_the_argument_type() {
+        assert_eq!(math("min", &[Integer(3), Float(1.5), Integer(2)]), Ok(Float(1.5)));
+        assert_eq!(math("max", &[Integer(3), Float(1.5), Integer(2)]), Ok(Integer(3)));
+        assert!(math("min", &[]).is_err());
+        assert!(math("max", &[Integer(1), text("2")]).is_err());
+    }
+
+    #[test]
+    fn clamp() {
+        assert_eq!(math("clamp", &[Integer(15), Integer(0), Integer(10)]), Ok(Integer(10)));
+        assert_eq!(math("clamp", &[Float(-0.5), Integer(0), Integer(10)]), Ok(Integer(0)));
+        assert_eq!(math("clamp", &[Float(2.5), Integer(0), Integer(10)]), Ok(Float(2.5)));
+        assert!(math("clamp", &[Integer(1), Integer(10), Integer(0)]).is_err());
+        assert!(math("clamp", &[Integer(1), Integer(10)]).is_err());
+    }
+
+    #[test]
+    fn rounding_returns_integers() {
+        assert_eq!(math("floor", &[Integer(7)]), Ok(Integer(7)));
+        assert_eq!(math("floor", &[Float(-1.5)]), Ok(Integer(-2)));
+        assert_eq!(math("ceil", &[Float(1.2)]), Ok(Integer(2)));
+        assert_eq!(math("round", &[Float(2.5)]), Ok(Integer(3)));
+        assert_eq!(math("round", &[Float(-2.5)]), Ok(Integer(-3)));
+        assert_eq!(math("round", &[Float(1.23456), Integer(2)]), Ok(Float(1.23)));
+        assert_eq!(math("abs", &[Integer(-4)]), Ok(Integer(4)));
+        assert!(math("abs", &[Integer(i64::MIN)]).is_err());
+    }
+
+    #[test]
+    fn pow_and_sqrt() {
+        assert_eq!(math("pow", &[Integer(2), Integer(10)]), Ok(Integer(1024)));
+        assert_eq!(math("pow", &[Integer(2), Float(0.5)]), Ok(Float(2f64.powf(0.5))));
+        assert!(math("pow", &[Integer(10), Integer(100)]).is_err());
+        assert_eq!(math("sqrt", &[Integer(9)]), Ok(Float(3.0)));
+        assert!(math("sqrt", &[Integer(-1)]).is_err());
+    }
+
+    #[test]
+    fn math_arguments_must_be_numbers() {
+        assert_eq!(math("abs", &[text("x")]), Err("abs() expects numbers".to_string()));
+        assert!(math("floor", &[Null]).is_err());
+    }
+
+    fn random(name: &str, values: &[Value]) -> Result<Value, String> {
+        Value::eval_random(name, values, &fastrand::Rng::with_seed(1)).expect("a random function")
+    }
+
+    #[test]
+    fn rand_rejects_empty_ranges() {
+        assert_eq!(random("rand", &[Integer(0)]), Err("rand(0) needs a positive limit".to_string()));
+        assert_eq!(random("rand", &[Integer(-1)]), Err("rand(-1) needs a positive limit".to_string()));
+        assert_eq!(random("rand", &[Integer(3), Integer(3)]), Err("rand(3, 3) is an empty range".to_string()));
+        assert_eq!(random("rand", &[Integer(1)]), Ok(Integer(0)));
+        assert_eq!(random("rand", &[Integer(3), Integer(4)]), Ok(Integer(3)));
+    }
+
+    #[test]
+    fn other_functions_are_not_math_functions() {
+        assert!(Value::eval_math("len", &[text("abc")]).is_none());
+        assert_eq!(Value::eval_function("len", vec![text("abc")]), Some(Integer(3)));
+    }
+
+    fn convert(name: &str, values: &[Value]) -> Result<Value, String> {
+        Value::eval_conversion(name, values).expect("a conversion function")
+    }
+
+    #[test]
+    fn number_conversions() {
+        assert_eq!(convert("number", &[text(" 42 ")]), Ok(Integer(42)));
+        assert_eq!(convert("number", &[text("-7")]), Ok(Integer(-7)));
+        assert_eq!(convert("number", &[text("2.5")]), Ok(Float(2.5)));
+        assert_eq!(convert("number", &[text("1e3")]), Ok(Float(1000.0)));
+        // Too large for an integer, so it becomes a float.
+        assert_eq!(convert("number", &[text("9223372036854775808")]), Ok(Float(9223372036854775808.0)));
+        assert_eq!(convert("number", &[Boolean(true)]), Ok(Integer(1)));
+        assert_eq!(convert("number", &[Float(0.5)]), Ok(Float(0.5)));
+    }
+
+    #[test]
+    fn number_rejects_what_isnt_a_number() {
+        assert_eq!(convert("number", &[text(" abc ")]), Err("number() can't convert 'abc'".to_string()));
+        assert!(convert("number", &[text("")]).is_err());
+        assert!(convert("number", &[text("inf")]).is_err());
+        assert!(convert("number", &[text("NaN")]).is_err());
+        assert_eq!(convert("number", &[Null]), Err("number() can't convert null".to_string()));
+        assert!(convert("number", &[]).is_err());
+        assert!(convert("number", &[text("1"), text("2")]).is_err());
+    }
+
+    #[test]
+    fn bool_conversions() {
+        for yes in ["true", "YES", " 1 ", "True"] {
+            assert_eq!(convert("bool", &[text(yes)]), Ok(Boolean(true)), "{}", yes);
+        }
+        for no in ["false", "No", "0", "", "  "] {
+            assert_eq!(convert("bool", &[text(no)]), Ok(Boolean(false)), "{}", no);
         }
+        assert_eq!(convert("bool", &[text("maybe")]), Err("bool() can't convert 'maybe'".to_string()));
+        assert_eq!(convert("bool", &[Integer(0)]), Ok(Boolean(false)));
+        assert_eq!(convert("bool", &[Array(vec![Null])]), Ok(Boolean(true)));
+        assert_eq!(convert("bool", &[Null]), Ok(Boolean(false)));
+        assert!(Value::eval_conversion("str", &[Null]).is_none());
     }
 }
@@ -0,0 +1,50 @@
+// Browser bindings, built with the "wasm" feature.
+use wasm_bindgen::prelude::*;
+use crate::story::{Interpreter, Story};
+use crate::value::Value;
+
+#[wasm_bindgen]
+pub struct WasmInterpreter {
+    interpreter: Interpreter
+}
+
+#[wasm_bindgen]
+impl WasmInterpreter {
+    #[wasm_bindgen(constructor)]
+    pub fn new(source: &str) -> Result<WasmInterpreter, JsError> {
+        match Story::new(source) {
+            Ok(story) => Ok(WasmInterpreter { interpreter: Interpreter::new(story) }),
+            Err(error) => Err(JsError::new(&error.to_string()))
+        }
+    }
+
+    pub fn play(&mut self) {
+        self.interpreter.play();
+    }
+
+    // Values are plain JSON: numbers, strings, booleans, arrays, objects or null.
+    pub fn send(&mut self, index: usize, json_value: &str) -> Result<(), JsError> {
+        let value = match serde_json::from_str::<serde_json::Value>(json_value) {
+            Ok(json) => Value::from_json(json),
+            Err(error) => return Err(JsError::new(&error.to_string()))
+        };
+        self.interpreter.send(index, value).map_err(|error| JsError::new(&error.to_string()))
+    }
+
+    // The output elements as a JSON array.
+    pub fn output(&self) -> Result<String, JsError> {
+        serde_json::to_string(&self.interpreter.output_elements()).map_err(|error| JsError::new(&error.to_string()))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.interpreter.is_finished()
+    }
+
+    pub fn dump_state(&self) -> Option<String> {
+        self.interpreter.dump_state()
+    }
+
+    pub fn load_state(&mut self, json: &str) -> Result<(), JsError> {
+        self.interpreter.load_state(json).map_err(|error| JsError::new(&error.to_string()))
+    }
+}